@@ -476,16 +476,46 @@ fileprivate struct FfiConverterString: FfiConverter {
         writeBytes(&buf, value.utf8)
     }
 }
-public func appleSync(host: String, port: UInt32, localDir: String, remoteDir: String) -> Bool {
+
+#if swift(>=5.8)
+@_documentation(visibility: private)
+#endif
+fileprivate struct FfiConverterOptionString: FfiConverterRustBuffer {
+    typealias SwiftType = String?
+
+    public static func write(_ value: SwiftType, into buf: inout [UInt8]) {
+        guard let value = value else {
+            writeInt(&buf, Int8(0))
+            return
+        }
+        writeInt(&buf, Int8(1))
+        FfiConverterString.write(value, into: &buf)
+    }
+
+    public static func read(from buf: inout (data: Data, offset: Data.Index)) throws -> SwiftType {
+        switch try readInt(&buf) as Int8 {
+        case 0: return nil
+        case 1: return try FfiConverterString.read(from: &buf)
+        default: throw UniffiInternalError.unexpectedOptionalTag
+        }
+    }
+}
+public func appleSync(host: String, port: UInt32, localDir: String, remoteDir: String, password: String? = nil) -> Bool {
     return try!  FfiConverterBool.lift(try! rustCall() {
     uniffi_ftp_client_fn_func_apple_sync(
         FfiConverterString.lower(host),
         FfiConverterUInt32.lower(port),
         FfiConverterString.lower(localDir),
-        FfiConverterString.lower(remoteDir),$0
+        FfiConverterString.lower(remoteDir),
+        FfiConverterOptionString.lower(password),$0
     )
 })
 }
+public func cancelSync() {try! rustCall() {
+    uniffi_ftp_client_fn_func_cancel_sync($0
+    )
+}
+}
 
 private enum InitializationResult {
     case ok
@@ -502,7 +532,10 @@ private var initializationResult: InitializationResult = {
     if bindings_contract_version != scaffolding_contract_version {
         return InitializationResult.contractVersionMismatch
     }
-    if (uniffi_ftp_client_checksum_func_apple_sync() != 2073) {
+    if (uniffi_ftp_client_checksum_func_apple_sync() != 8943) {
+        return InitializationResult.apiChecksumMismatch
+    }
+    if (uniffi_ftp_client_checksum_func_cancel_sync() != 30923) {
         return InitializationResult.apiChecksumMismatch
     }
 
@@ -253,7 +253,13 @@ typedef void (*UniffiForeignFutureCompleteVoid)(uint64_t, UniffiForeignFutureStr
 #endif
 #ifndef UNIFFI_FFIDEF_UNIFFI_FTP_CLIENT_FN_FUNC_APPLE_SYNC
 #define UNIFFI_FFIDEF_UNIFFI_FTP_CLIENT_FN_FUNC_APPLE_SYNC
-int8_t uniffi_ftp_client_fn_func_apple_sync(RustBuffer host, uint32_t port, RustBuffer local_dir, RustBuffer remote_dir, RustCallStatus *_Nonnull out_status
+int8_t uniffi_ftp_client_fn_func_apple_sync(RustBuffer host, uint32_t port, RustBuffer local_dir, RustBuffer remote_dir, RustBuffer password, RustCallStatus *_Nonnull out_status
+);
+#endif
+#ifndef UNIFFI_FFIDEF_UNIFFI_FTP_CLIENT_FN_FUNC_CANCEL_SYNC
+#define UNIFFI_FFIDEF_UNIFFI_FTP_CLIENT_FN_FUNC_CANCEL_SYNC
+void uniffi_ftp_client_fn_func_cancel_sync(RustCallStatus *_Nonnull out_status
+    
 );
 #endif
 #ifndef UNIFFI_FFIDEF_FFI_FTP_CLIENT_RUSTBUFFER_ALLOC
@@ -540,6 +546,12 @@ void ffi_ftp_client_rust_future_complete_void(uint64_t handle, RustCallStatus *_
 #define UNIFFI_FFIDEF_UNIFFI_FTP_CLIENT_CHECKSUM_FUNC_APPLE_SYNC
 uint16_t uniffi_ftp_client_checksum_func_apple_sync(void
     
+);
+#endif
+#ifndef UNIFFI_FFIDEF_UNIFFI_FTP_CLIENT_CHECKSUM_FUNC_CANCEL_SYNC
+#define UNIFFI_FFIDEF_UNIFFI_FTP_CLIENT_CHECKSUM_FUNC_CANCEL_SYNC
+uint16_t uniffi_ftp_client_checksum_func_cancel_sync(void
+    
 );
 #endif
 #ifndef UNIFFI_FFIDEF_FFI_FTP_CLIENT_UNIFFI_CONTRACT_VERSION
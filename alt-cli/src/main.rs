@@ -2,14 +2,63 @@ use std::io::{Read, Write};
 use std::str::FromStr;
 use colored::*;
 use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 mod client;
+mod reply;
+
+fn configure(ftp_client: client::FtpClient, matches: &clap::ArgMatches) -> client::FtpClient {
+    let ftp_client = match matches.value_of("compress") {
+        Some(level) => ftp_client.with_compression(level.parse().expect("Invalid compression level")),
+        None => ftp_client,
+    };
+    let ftp_client = match matches.value_of("segments") {
+        Some(segments) => ftp_client.with_segments(segments.parse().expect("Invalid number of segments")),
+        None => ftp_client,
+    };
+    match matches.value_of("password") {
+        Some(password) => ftp_client.with_password(password.to_string()),
+        None => ftp_client,
+    }
+}
 
 fn main() {
+    // The first Ctrl-C aborts the transfer in progress, a second one exits right away.
+    let cancel = Arc::new(AtomicBool::new(false));
+    let handler_cancel = Arc::clone(&cancel);
+    ctrlc::set_handler(move || {
+        if handler_cancel.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+    })
+    .expect("Couldn't set the Ctrl-C handler");
+
     loop {
         let matches = clap::App::new("FTP Client")
             .version("1.0")
             .author("Your Name")
             .about("FTP Client for testing and syncing")
+            .arg(
+                clap::Arg::with_name("compress")
+                    .long("compress")
+                    .help("Use MODE Z with this zlib level (0-9) when the server supports it")
+                    .takes_value(true)
+                    .global(true),
+            )
+            .arg(
+                clap::Arg::with_name("segments")
+                    .long("segments")
+                    .help("Transfer files of 64 MiB or more over this many data connections at once")
+                    .takes_value(true)
+                    .global(true),
+            )
+            .arg(
+                clap::Arg::with_name("password")
+                    .long("password")
+                    .help("Password to log in with, when the server asks for one")
+                    .takes_value(true)
+                    .global(true),
+            )
             .subcommand(
                 clap::SubCommand::with_name("upload")
                     .about("Upload a file")
@@ -86,7 +135,7 @@ fn main() {
                     .expect("Invalid port number");
                 let file = upload_matches.value_of("file").unwrap();
 
-                let ftp_client = client::FtpClient::new(host.to_string(), port);
+                let ftp_client = configure(client::FtpClient::new(host.to_string(), port), upload_matches).with_cancel(Arc::clone(&cancel));
                 if let Err(e) = ftp_client.upload_file(file) {
                     eprintln!("Error uploading file: {}", e);
                     success = false;
@@ -101,7 +150,7 @@ fn main() {
                     .expect("Invalid port number");
                 let file = download_matches.value_of("file").unwrap();
 
-                let ftp_client = client::FtpClient::new(host.to_string(), port);
+                let ftp_client = configure(client::FtpClient::new(host.to_string(), port), download_matches).with_cancel(Arc::clone(&cancel));
                 if let Err(e) = ftp_client.download_file(file) {
                     eprintln!("Error downloading file: {}", e);
                     success = false;
@@ -117,7 +166,7 @@ fn main() {
                 let local_dir = sync_matches.value_of("local-dir").unwrap();
                 let remote_dir = sync_matches.value_of("remote-dir").unwrap();
 
-                let ftp_client = client::FtpClient::new(host.to_string(), port);
+                let ftp_client = configure(client::FtpClient::new(host.to_string(), port), sync_matches).with_cancel(Arc::clone(&cancel));
                 if let Err(e) = ftp_client.sync(local_dir, remote_dir) {
                     eprintln!("Error syncing directories: {}", e);
                     success = false;
@@ -129,7 +178,10 @@ fn main() {
             }
         }
 
-        if success {
+        if cancel.load(Ordering::SeqCst) {
+            println!("Cancelled.");
+            break;
+        } else if success {
             break;
         } else {
             println!("Operation failed. Restarting in 1 seconds...");
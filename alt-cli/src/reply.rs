@@ -0,0 +1,105 @@
+use std::fmt;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+/// A complete server reply, with every line of a multi-line reply.
+#[derive(Debug)]
+pub struct Reply {
+    pub code: u32,
+    pub lines: Vec<String>,
+}
+
+impl Reply {
+    /// Text of all lines, without the leading codes.
+    pub fn text(&self) -> String {
+        self.lines.join("\n")
+    }
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.code, self.text())
+    }
+}
+
+/// Control connection that sends commands and reads whole replies, keeping any
+/// bytes that belong to the next reply for the next read.
+pub struct ControlStream {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+}
+
+impl ControlStream {
+    pub fn new(stream: TcpStream) -> Self {
+        ControlStream {
+            stream,
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn send(&mut self, command: &str) -> std::io::Result<()> {
+        self.stream.write_all(format!("{}\r\n", command).as_bytes())
+    }
+
+    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    /// Sends ABOR after the Telnet IP and Synch (IAC IP IAC DM), as RFC 959 asks.
+    pub fn send_abort(&mut self) -> std::io::Result<()> {
+        self.stream.write_all(&[0xFF, 0xF4, 0xFF, 0xF2])?;
+        self.send("ABOR")
+    }
+
+    /// Reads until the final line of the next reply (`NNN text`, after any `NNN-` lines).
+    pub fn read_reply(&mut self) -> std::io::Result<Reply> {
+        let first = self.read_line()?;
+        let code = parse_code(&first)?;
+        let mut lines = vec![first[4.min(first.len())..].to_string()];
+
+        if first.as_bytes().get(3) == Some(&b'-') {
+            loop {
+                let line = self.read_line()?;
+                if line.len() >= 4 && line.starts_with(&first[..3]) && line.as_bytes()[3] == b' ' {
+                    lines.push(line[4..].to_string());
+                    break;
+                }
+                lines.push(line.strip_prefix(' ').unwrap_or(&line).to_string());
+            }
+        }
+
+        Ok(Reply { code, lines })
+    }
+
+    fn read_line(&mut self) -> std::io::Result<String> {
+        loop {
+            if let Some(end) = self.buffer.windows(2).position(|w| w == b"\r\n") {
+                let line = String::from_utf8_lossy(&self.buffer[..end]).to_string();
+                self.buffer.drain(..end + 2);
+                return Ok(line);
+            }
+
+            let mut chunk = [0u8; 1024];
+            let n = self.stream.read(&mut chunk)?;
+            if n == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed by server"));
+            }
+            self.buffer.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+fn parse_code(line: &str) -> std::io::Result<u32> {
+    let valid = line.len() >= 3
+        && line.as_bytes()[..3].iter().all(u8::is_ascii_digit)
+        && matches!(line.as_bytes().get(3), None | Some(b' ') | Some(b'-'));
+    if !valid {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Malformed reply: {}", line)));
+    }
+    Ok(line[..3].parse().unwrap_or_default())
+}
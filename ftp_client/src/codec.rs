@@ -1,12 +1,37 @@
-use std::fs::{self, File};
-use std::io::{Read, Write};
-use std::net::TcpStream;
-use std::path::Path;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::{IpAddr, Ipv4Addr, TcpStream};
+use std::path::{Component, Path};
 use std::str::FromStr;
 use colored::*;
-use std::io::BufReader;
 use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::collections::HashMap;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use crate::reply::ControlStream;
+use sha2::{Digest, Sha256};
+
+/// Files at least this large are transferred in segments, when segments are enabled.
+const SEGMENT_THRESHOLD: u64 = 64 * 1024 * 1024;
+/// How long to wait for the server to hash a file.
+const HASH_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Whether a PASV address can't be reached from outside the server's network:
+/// private, shared (carrier-grade NAT), loopback, link-local or unspecified.
+fn unroutable(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast() || (a == 100 && b & 0xC0 == 64)
+}
+
+/// An entry of a recursive remote listing, with its path relative to the listed directory.
+struct RemoteEntry {
+    path: String,
+    size: u64,
+    is_dir: bool,
+}
 
 pub struct FtpClient {
     ftp_host: String,
@@ -14,6 +39,12 @@ pub struct FtpClient {
     timeout: Duration,
     max_retries: u32,
     retry_delay: Duration,
+    compression: Option<u32>,
+    /// Data connections used at once for a large file.
+    segments: u32,
+    /// Sent with PASS when the server asks for one.
+    password: Option<String>,
+    cancel: Arc<AtomicBool>,
 }
 
 impl FtpClient {
@@ -24,9 +55,39 @@ impl FtpClient {
             timeout: Duration::from_millis(500),
             max_retries: 3,
             retry_delay: Duration::from_millis(300),
+            compression: None,
+            segments: 1,
+            password: None,
+            cancel: Arc::new(AtomicBool::new(false)),
         }
     }
 
+    /// Uses MODE Z with the given zlib level (0-9) when the server advertises it.
+    pub fn with_compression(mut self, level: u32) -> Self {
+        self.compression = Some(level.min(9));
+        self
+    }
+
+    /// Transfers files of 64 MiB or more over `segments` data connections at once, when
+    /// the server supports ranged transfers, and checks them by SHA-256 afterwards.
+    pub fn with_segments(mut self, segments: u32) -> Self {
+        self.segments = segments.max(1);
+        self
+    }
+
+    /// Logs in with `password` when the server replies to USER with 331.
+    pub fn with_password(mut self, password: String) -> Self {
+        self.password = Some(password);
+        self
+    }
+
+    /// Cancels the transfer in progress, with ABOR, once `cancel` is set. The operation
+    /// then fails with `ErrorKind::Interrupted`.
+    pub fn with_cancel(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
     fn print_colored(&self, message: &str, color: &str) {
         match color {
             "purple" => println!("{}", message.purple()),
@@ -39,7 +100,7 @@ impl FtpClient {
         }
     }
 
-    fn connect(&self) -> std::io::Result<TcpStream> {
+    fn connect(&self) -> std::io::Result<ControlStream> {
         for attempt in 0..self.max_retries {
             self.print_colored(
                 &format!(
@@ -50,16 +111,15 @@ impl FtpClient {
             );
 
             match TcpStream::connect((&*self.ftp_host, self.ftp_port)) {
-                Ok(mut stream) => {
+                Ok(stream) => {
                     stream.set_read_timeout(Some(self.timeout))?;
                     stream.set_write_timeout(Some(self.timeout))?;
+                    let mut stream = ControlStream::new(stream);
 
-                    let mut response = [0u8; 1024];
-                    let n = stream.read(&mut response)?;
-                    let response_str = String::from_utf8_lossy(&response[..n]);
-                    self.print_colored(&format!("Response: {}", response_str), "cyan");
+                    let reply = stream.read_reply()?;
+                    self.print_colored(&format!("Response: {}", reply), "cyan");
 
-                    if !response_str.contains("220") {
+                    if reply.code != 220 {
                         return Err(std::io::Error::new(
                             std::io::ErrorKind::Other,
                             "Invalid FTP welcome message",
@@ -85,7 +145,7 @@ impl FtpClient {
         unreachable!()
     }
 
-    fn login(&self, stream: &mut TcpStream, user: &str) -> std::io::Result<()> {
+    fn login(&self, stream: &mut ControlStream, user: &str) -> std::io::Result<()> {
         for attempt in 0..self.max_retries {
             match self.attempt_login(stream, user) {
                 Ok(_) => return Ok(()),
@@ -105,16 +165,25 @@ impl FtpClient {
         unreachable!()
     }
 
-    fn attempt_login(&self, stream: &mut TcpStream, user: &str) -> std::io::Result<()> {
-        let command = format!("USER {}\r\n", user);
-        stream.write_all(command.as_bytes())?;
-
-        let mut response = [0u8; 1024];
-        let n = stream.read(&mut response)?;
-        let response_str = String::from_utf8_lossy(&response[..n]);
-        self.print_colored(&format!("Response after login: {}", response_str), "cyan");
+    fn attempt_login(&self, stream: &mut ControlStream, user: &str) -> std::io::Result<()> {
+        stream.send(&format!("USER {}", user))?;
+
+        let mut reply = stream.read_reply()?;
+        self.print_colored(&format!("Response after login: {}", reply), "cyan");
+
+        if reply.code == 331 {
+            let Some(password) = &self.password else {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    "The server asks for a password, but none was given",
+                ));
+            };
+            stream.send(&format!("PASS {}", password))?;
+            reply = stream.read_reply()?;
+            self.print_colored(&format!("Response after password: {}", reply), "cyan");
+        }
 
-        if !response_str.contains("230") {
+        if reply.code != 230 {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::Other,
                 "Login failed",
@@ -124,7 +193,106 @@ impl FtpClient {
         Ok(())
     }
 
-    fn pasv_mode(&self, stream: &mut TcpStream) -> std::io::Result<(String, u16)> {
+    /// Features advertised by FEAT, in upper case; none if the server doesn't support FEAT.
+    fn features(&self, stream: &mut ControlStream) -> std::io::Result<Vec<String>> {
+        stream.send("FEAT")?;
+        let reply = stream.read_reply()?;
+        if reply.code != 211 {
+            return Ok(Vec::new());
+        }
+        Ok(reply.lines.iter().map(|line| line.trim().to_ascii_uppercase()).collect())
+    }
+
+    /// Switches the session to MODE Z if compression is wanted and the server supports it.
+    fn negotiate_compression(&self, stream: &mut ControlStream) -> std::io::Result<bool> {
+        if self.compression.is_none() {
+            return Ok(false);
+        }
+
+        if !self.features(stream)?.iter().any(|feature| feature == "MODE Z") {
+            return Ok(false);
+        }
+
+        stream.send("MODE Z")?;
+        let reply = stream.read_reply()?;
+        self.print_colored(&format!("Response after MODE Z: {}", reply), "cyan");
+
+        Ok(reply.code == 200)
+    }
+
+    /// Sends `reader` over the data connection, deflating it in MODE Z.
+    fn send_data(&self, control: &mut ControlStream, reader: &mut impl Read, data_stream: TcpStream, compressed: bool) -> std::io::Result<()> {
+        let mut buffer = [0; 4096];
+        if compressed {
+            let level = Compression::new(self.compression.unwrap_or(6));
+            let mut encoder = ZlibEncoder::new(data_stream, level);
+            loop {
+                if self.cancel.load(Ordering::SeqCst) {
+                    return Err(self.abort(control));
+                }
+                let n = reader.read(&mut buffer)?;
+                if n == 0 {
+                    break;
+                }
+                encoder.write_all(&buffer[..n])?;
+            }
+            encoder.finish()?;
+        } else {
+            let mut data_stream = data_stream;
+            loop {
+                if self.cancel.load(Ordering::SeqCst) {
+                    return Err(self.abort(control));
+                }
+                let n = reader.read(&mut buffer)?;
+                if n == 0 {
+                    break;
+                }
+                data_stream.write_all(&buffer[..n])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Receives the data connection into `writer`, inflating it in MODE Z.
+    fn receive_data(&self, control: &mut ControlStream, data_stream: impl Read + 'static, writer: &mut impl Write, compressed: bool) -> std::io::Result<()> {
+        let mut reader: Box<dyn Read> = if compressed {
+            Box::new(ZlibDecoder::new(data_stream))
+        } else {
+            Box::new(data_stream)
+        };
+        let mut buffer = [0; 4096];
+        loop {
+            if self.cancel.load(Ordering::SeqCst) {
+                return Err(self.abort(control));
+            }
+            let n = reader.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            writer.write_all(&buffer[..n])?;
+        }
+        Ok(())
+    }
+
+    /// Cancels the transfer in progress: sends ABOR, then reads the 426 for the
+    /// interrupted transfer, if the server sends one, and the reply to ABOR.
+    fn abort(&self, control: &mut ControlStream) -> std::io::Error {
+        self.print_colored("Cancelling transfer...", "yellow");
+        let result = control.send_abort().and_then(|()| {
+            let mut reply = control.read_reply()?;
+            if reply.code == 426 {
+                reply = control.read_reply()?;
+            }
+            self.print_colored(&format!("Response after ABOR: {}", reply), "yellow");
+            Ok(())
+        });
+        if let Err(e) = result {
+            self.print_colored(&format!("ABOR failed: {}", e), "red");
+        }
+        std::io::Error::new(std::io::ErrorKind::Interrupted, "Transfer cancelled")
+    }
+
+    fn pasv_mode(&self, stream: &mut ControlStream) -> std::io::Result<(String, u16)> {
         for attempt in 0..self.max_retries {
             match self.attempt_pasv_mode(stream) {
                 Ok(result) => return Ok(result),
@@ -145,15 +313,13 @@ impl FtpClient {
         unreachable!()
     }
 
-    fn attempt_pasv_mode(&self, stream: &mut TcpStream) -> std::io::Result<(String, u16)> {
-        stream.write_all(b"PASV\r\n")?;
+    fn attempt_pasv_mode(&self, stream: &mut ControlStream) -> std::io::Result<(String, u16)> {
+        stream.send("PASV")?;
 
-        let mut response = [0u8; 1024];
-        let n = stream.read(&mut response)?;
-        let response_str = String::from_utf8_lossy(&response[..n]);
-        self.print_colored(&format!("Response after PASV: {}", response_str), "yellow");
+        let reply = stream.read_reply()?;
+        self.print_colored(&format!("Response after PASV: {}", reply), "yellow");
 
-        if !response_str.contains("227") {
+        if reply.code != 227 {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::Other,
                 "PASV mode failed",
@@ -161,6 +327,7 @@ impl FtpClient {
         }
 
         // Parse PASV response (same as before)
+        let response_str = reply.text();
         let start = response_str.find('(').unwrap() + 1;
         let end = response_str.find(')').unwrap();
         let pasv_info: Vec<u8> = response_str[start..end]
@@ -168,12 +335,23 @@ impl FtpClient {
             .map(|s| u8::from_str(s.trim()).unwrap())
             .collect();
 
-        let data_host = format!(
-            "{}.{}.{}.{}",
-            pasv_info[0], pasv_info[1], pasv_info[2], pasv_info[3]
-        );
+        let advertised = Ipv4Addr::new(pasv_info[0], pasv_info[1], pasv_info[2], pasv_info[3]);
         let data_port = (pasv_info[4] as u16 * 256) + pasv_info[5] as u16;
 
+        // Behind a NAT or in a container, the server may advertise an address only
+        // reachable from its own network. The control host is the same server, but only
+        // over IPv4 is it sure to be listening for the data connection too.
+        let data_host = match stream.peer_addr().map(|peer| peer.ip()) {
+            Ok(IpAddr::V4(peer)) if unroutable(advertised) && peer != advertised => {
+                self.print_colored(
+                    &format!("Server advertised unreachable address {}, using {}", advertised, peer),
+                    "yellow",
+                );
+                peer.to_string()
+            }
+            _ => advertised.to_string(),
+        };
+
         Ok((data_host, data_port))
     }
 
@@ -186,6 +364,7 @@ impl FtpClient {
         for attempt in 0..self.max_retries {
             match self.attempt_upload_file(filename) {
                 Ok(_) => return Ok(()),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => return Err(e),
                 Err(e) => {
                     if attempt < self.max_retries - 1 {
                         self.print_colored(
@@ -203,20 +382,22 @@ impl FtpClient {
     }
 
     fn attempt_upload_file(&self, filename: &str) -> std::io::Result<()> {
+        if self.upload_segmented(filename)? {
+            return Ok(());
+        }
+
         let mut control_stream = self.connect()?;
         self.login(&mut control_stream, "testuser")?;
+        let compressed = self.negotiate_compression(&mut control_stream)?;
         let (data_host, data_port) = self.pasv_mode(&mut control_stream)?;
 
-        let mut data_stream = TcpStream::connect((data_host, data_port))?;
+        let data_stream = TcpStream::connect((data_host, data_port))?;
 
-        let command = format!("STOR {}\r\n", filename);
-        control_stream.write_all(command.as_bytes())?;
+        control_stream.send(&format!("STOR {}", filename))?;
 
-        let mut response = [0u8; 1024];
-        let n = control_stream.read(&mut response)?;
-        let response_str = String::from_utf8_lossy(&response[..n]);
+        let reply = control_stream.read_reply()?;
 
-        if !response_str.contains("150") {
+        if reply.code != 150 {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::Other,
                 "Failed to initiate file transfer",
@@ -224,23 +405,11 @@ impl FtpClient {
         }
 
         let mut file = File::open(filename)?;
-        let mut buffer = [0; 4096];
-
-        loop {
-            let n = file.read(&mut buffer)?;
-            if n == 0 {
-                break;
-            }
-            data_stream.write_all(&buffer[..n])?;
-        }
-
-        drop(data_stream);
+        self.send_data(&mut control_stream, &mut file, data_stream, compressed)?;
 
-        let mut response = [0u8; 1024];
-        let n = control_stream.read(&mut response)?;
-        let response_str = String::from_utf8_lossy(&response[..n]);
+        let reply = control_stream.read_reply()?;
         self.print_colored(
-            &format!("Upload of {} completed: {}", filename, response_str),
+            &format!("Upload of {} completed: {}", filename, reply),
             "blue",
         );
         drop(control_stream);
@@ -251,6 +420,7 @@ impl FtpClient {
         for attempt in 0..self.max_retries {
             match self.attempt_download_file(filename) {
                 Ok(_) => return Ok(()),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => return Err(e),
                 Err(e) => {
                     if attempt < self.max_retries - 1 {
                         self.print_colored(
@@ -269,11 +439,16 @@ impl FtpClient {
     }
 
     fn attempt_download_file(&self, filename: &str) -> std::io::Result<()> {
+        if self.download_segmented(filename, Path::new(filename), None)? {
+            return Ok(());
+        }
+
         let mut control_stream = self.connect()?;
         self.login(&mut control_stream, "testuser")?;
+        let compressed = self.negotiate_compression(&mut control_stream)?;
         let (data_host, data_port) = self.pasv_mode(&mut control_stream)?;
 
-        let mut data_stream = loop {
+        let data_stream = loop {
             match TcpStream::connect((data_host.clone(), data_port)) { // Clone data_host
                 Ok(stream) => break stream,
                 Err(e) => {
@@ -286,14 +461,11 @@ impl FtpClient {
             }
         };
 
-        let command = format!("RETR {}\r\n", filename);
-        control_stream.write_all(command.as_bytes())?;
+        control_stream.send(&format!("RETR {}", filename))?;
 
-        let mut response = [0u8; 1024];
-        let n = control_stream.read(&mut response)?;
-        let response_str = String::from_utf8_lossy(&response[..n]);
+        let reply = control_stream.read_reply()?;
 
-        if !response_str.contains("150") {
+        if reply.code != 150 {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::Other,
                 "Failed to initiate file download",
@@ -301,25 +473,321 @@ impl FtpClient {
         }
 
         let mut file = File::create(filename)?;
-        let mut buffer = [0; 4096];
+        self.receive_data(&mut control_stream, data_stream, &mut file, compressed)?;
 
-        loop {
-            let n = data_stream.read(&mut buffer)?;
-            if n == 0 {
-                break;
+        let reply = control_stream.read_reply()?;
+        self.print_colored(
+            &format!("Download of {} completed: {}", filename, reply),
+            "blue",
+        );
+        drop(control_stream);
+        Ok(())
+    }
+
+    /// Whether the server supports what segmented transfers need: REST for RETR and STOR,
+    /// SIZE, and HASH to check the result.
+    fn supports_segments(&self, stream: &mut ControlStream) -> std::io::Result<bool> {
+        let features = self.features(stream)?;
+        Ok(["REST STREAM", "SIZE", "HASH SHA-256"]
+            .iter()
+            .all(|wanted| features.iter().any(|feature| feature == wanted)))
+    }
+
+    /// Splits `size` bytes into at most `self.segments` ranges, as (offset, length).
+    fn segment_ranges(&self, size: u64) -> Vec<(u64, u64)> {
+        let length = size.div_ceil(self.segments as u64).max(1);
+        (0..size)
+            .step_by(length as usize)
+            .map(|offset| (offset, length.min(size - offset)))
+            .collect()
+    }
+
+    /// Runs `transfer` for every range of `size` bytes, each in its own thread and
+    /// retried on its own, and returns the first error.
+    fn run_segments(
+        &self,
+        size: u64,
+        transfer: impl Fn(u64, u64) -> std::io::Result<()> + Sync,
+    ) -> std::io::Result<()> {
+        let transfer = &transfer;
+        let results: Vec<std::io::Result<()>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .segment_ranges(size)
+                .into_iter()
+                .map(|(offset, length)| {
+                    scope.spawn(move || {
+                        for attempt in 0..self.max_retries {
+                            match transfer(offset, length) {
+                                Ok(_) => return Ok(()),
+                                Err(e) if matches!(e.kind(), std::io::ErrorKind::Interrupted | std::io::ErrorKind::Unsupported) => return Err(e),
+                                Err(e) => {
+                                    if attempt < self.max_retries - 1 {
+                                        self.print_colored(
+                                            &format!("Segment at {} failed: {}. Retrying...", offset, e),
+                                            "yellow",
+                                        );
+                                        std::thread::sleep(self.retry_delay);
+                                    } else {
+                                        return Err(e);
+                                    }
+                                }
+                            }
+                        }
+                        unreachable!()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle.join().unwrap_or_else(|_| {
+                        Err(std::io::Error::new(std::io::ErrorKind::Other, "Segment thread panicked"))
+                    })
+                })
+                .collect()
+        });
+        results.into_iter().collect()
+    }
+
+    /// Opens a data connection with PASV.
+    fn data_connection(&self, stream: &mut ControlStream) -> std::io::Result<TcpStream> {
+        let (data_host, data_port) = self.pasv_mode(stream)?;
+        for attempt in 0..self.max_retries {
+            match TcpStream::connect((data_host.clone(), data_port)) {
+                Ok(data_stream) => return Ok(data_stream),
+                Err(e) if attempt < self.max_retries - 1 => {
+                    self.print_colored(
+                        &format!("Failed to connect to data port: {}. Retrying...", e),
+                        "yellow",
+                    );
+                    std::thread::sleep(self.retry_delay);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        unreachable!()
+    }
+
+    /// Size of a remote file, from SIZE.
+    fn remote_size(&self, stream: &mut ControlStream, remote_path: &str) -> std::io::Result<u64> {
+        stream.send(&format!("SIZE {}", remote_path))?;
+        let reply = stream.read_reply()?;
+        if reply.code != 213 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("SIZE failed: {}", reply),
+            ));
+        }
+        reply.text().trim().parse().map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Invalid SIZE reply: {}", reply))
+        })
+    }
+
+    /// Checks a local file against the SHA-256 the server computes for `remote_path`.
+    fn verify_hash(&self, stream: &mut ControlStream, remote_path: &str, local_path: &Path) -> std::io::Result<()> {
+        // Hashing a large file takes the server a while.
+        stream.set_read_timeout(Some(HASH_TIMEOUT))?;
+        stream.send(&format!("HASH {}", remote_path))?;
+        let reply = stream.read_reply()?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        // 213 SHA-256 <range> <hash> <path>
+        let text = reply.text();
+        let remote = match text.split_whitespace().nth(2) {
+            Some(hash) if reply.code == 213 => hash.to_ascii_lowercase(),
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("HASH failed: {}", reply),
+                ))
             }
-            file.write_all(&buffer[..n])?;
+        };
+
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut File::open(local_path)?, &mut hasher)?;
+        let local: String = hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect();
+        if local != remote {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("SHA-256 mismatch for {}: local {}, remote {}", remote_path, local, remote),
+            ));
+        }
+        self.print_colored(&format!("Verified {} (SHA-256 {})", remote_path, local), "green");
+        Ok(())
+    }
+
+    /// Downloads `remote_path` in parallel segments when it's large enough and the server
+    /// supports it, and checks the result by hash. Returns false, having done nothing,
+    /// otherwise.
+    fn download_segmented(&self, remote_path: &str, local_path: &Path, size: Option<u64>) -> std::io::Result<bool> {
+        if self.segments < 2 || size.is_some_and(|size| size < SEGMENT_THRESHOLD) {
+            return Ok(false);
+        }
+        let mut control_stream = self.connect()?;
+        self.login(&mut control_stream, "testuser")?;
+        if !self.supports_segments(&mut control_stream)? {
+            return Ok(false);
+        }
+        let size = match size {
+            Some(size) => size,
+            None => self.remote_size(&mut control_stream, remote_path)?,
+        };
+        if size < SEGMENT_THRESHOLD {
+            return Ok(false);
         }
 
-        let mut response = [0u8; 1024];
-        let n = control_stream.read(&mut response)?;
-        let response_str = String::from_utf8_lossy(&response[..n]);
         self.print_colored(
-            &format!("Download of {} completed: {}", filename, response_str),
-            "blue",
+            &format!("Downloading {} in {} segments", remote_path, self.segment_ranges(size).len()),
+            "purple",
         );
-        drop(control_stream);
+        File::create(local_path)?.set_len(size)?;
+        self.run_segments(size, |offset, length| self.download_segment(remote_path, local_path, offset, length))?;
+        self.verify_hash(&mut control_stream, remote_path, local_path)?;
+        Ok(true)
+    }
+
+    /// Downloads `length` bytes of `remote_path` from `offset` into the same range of
+    /// `local_path`, over a connection of its own.
+    fn download_segment(&self, remote_path: &str, local_path: &Path, offset: u64, length: u64) -> std::io::Result<()> {
+        let mut control_stream = self.connect()?;
+        self.login(&mut control_stream, "testuser")?;
+        let data_stream = self.data_connection(&mut control_stream)?;
+
+        control_stream.send(&format!("REST {}", offset))?;
+        let reply = control_stream.read_reply()?;
+        if reply.code != 350 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("REST failed: {}", reply),
+            ));
+        }
+        control_stream.send(&format!("RETR {}", remote_path))?;
+        let reply = control_stream.read_reply()?;
+        if reply.code != 150 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "Failed to initiate file download",
+            ));
+        }
+
+        let mut file = OpenOptions::new().write(true).open(local_path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        // Closing the data connection at the end of the range stops the server.
+        self.receive_data(&mut control_stream, data_stream.take(length), &mut file, false)?;
+        if file.stream_position()? != offset + length {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("Segment at {} ended early", offset),
+            ));
+        }
+
+        // 426 when the server still had data to send past the range.
+        let reply = control_stream.read_reply()?;
+        if reply.code != 226 && reply.code != 426 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("Segment download failed: {}", reply),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Uploads `filename` in parallel segments when it's large enough and the server
+    /// supports it, and checks the result by hash. Returns false, having done nothing,
+    /// otherwise.
+    fn upload_segmented(&self, filename: &str) -> std::io::Result<bool> {
+        let size = fs::metadata(filename)?.len();
+        if self.segments < 2 || size < SEGMENT_THRESHOLD {
+            return Ok(false);
+        }
+        let mut control_stream = self.connect()?;
+        self.login(&mut control_stream, "testuser")?;
+        if !self.supports_segments(&mut control_stream)? {
+            return Ok(false);
+        }
+
+        // Segments write into the existing file, so start from an empty one. ALLO tells
+        // the server the upload goes on in segments, to announce it once they're all in;
+        // a server without it just replies with an error.
+        let data_stream = self.data_connection(&mut control_stream)?;
+        control_stream.send(&format!("ALLO {}", size))?;
+        control_stream.read_reply()?;
+        control_stream.send(&format!("STOR {}", filename))?;
+        let reply = control_stream.read_reply()?;
+        if reply.code != 150 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "Failed to initiate file transfer",
+            ));
+        }
         drop(data_stream);
+        let reply = control_stream.read_reply()?;
+        if reply.code != 226 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("Upload failed: {}", reply),
+            ));
+        }
+
+        self.print_colored(
+            &format!("Uploading {} in {} segments", filename, self.segment_ranges(size).len()),
+            "purple",
+        );
+        match self.run_segments(size, |offset, length| self.upload_segment(filename, offset, length)) {
+            Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+                self.print_colored("Server can't store segments, uploading in one piece", "yellow");
+                return Ok(false);
+            }
+            result => result?,
+        }
+        self.verify_hash(&mut control_stream, filename, Path::new(filename))?;
+        Ok(true)
+    }
+
+    /// Uploads `length` bytes of `filename` from `offset` into the same range of the
+    /// remote file, over a connection of its own.
+    fn upload_segment(&self, filename: &str, offset: u64, length: u64) -> std::io::Result<()> {
+        let mut control_stream = self.connect()?;
+        self.login(&mut control_stream, "testuser")?;
+        let data_stream = self.data_connection(&mut control_stream)?;
+
+        control_stream.send(&format!("REST {}", offset))?;
+        let reply = control_stream.read_reply()?;
+        if reply.code != 350 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("REST failed: {}", reply),
+            ));
+        }
+        control_stream.send(&format!("STOR {}", filename))?;
+        let reply = control_stream.read_reply()?;
+        match reply.code {
+            150 => {}
+            // 553: the storage only writes up to the end of the file, not into a gap.
+            504 | 553 => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    format!("Server can't store segments: {}", reply),
+                ))
+            }
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "Failed to initiate file transfer",
+                ))
+            }
+        }
+
+        let mut file = File::open(filename)?;
+        file.seek(SeekFrom::Start(offset))?;
+        self.send_data(&mut control_stream, &mut file.take(length), data_stream, false)?;
+
+        let reply = control_stream.read_reply()?;
+        if reply.code != 226 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("Segment upload failed: {}", reply),
+            ));
+        }
         Ok(())
     }
 
@@ -332,6 +800,7 @@ impl FtpClient {
         for attempt in 0..self.max_retries {
             match self.attempt_sync(local_dir, remote_dir) {
                 Ok(_) => return Ok(()),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => return Err(e),
                 Err(e) => {
                     if attempt < self.max_retries - 1 {
                         self.print_colored(
@@ -417,6 +886,54 @@ impl FtpClient {
     }
 
     fn sync_remote_to_local(&self, local_dir: &str, remote_dir: &str) -> std::io::Result<()> {
+        // The whole tree in one listing when the server supports it, rather than a
+        // connection per directory.
+        if let Some(tree) = self.list_tree(remote_dir)? {
+            return self.sync_tree_to_local(local_dir, remote_dir, tree);
+        }
+        self.sync_remote_dir_to_local(local_dir, remote_dir)
+    }
+
+    /// Downloads the files of a recursive listing that are missing locally or differ in size.
+    fn sync_tree_to_local(&self, local_dir: &str, remote_dir: &str, tree: Vec<RemoteEntry>) -> std::io::Result<()> {
+        fs::create_dir_all(local_dir)?;
+
+        for entry in tree {
+            // Never write outside the local directory, whatever the server sends.
+            if !Path::new(&entry.path).components().all(|component| matches!(component, Component::Normal(_))) {
+                self.print_colored(&format!("Skipping invalid remote path {:?}", entry.path), "red");
+                continue;
+            }
+            let local_path = Path::new(local_dir).join(&entry.path);
+            let remote_path = Path::new(remote_dir).join(&entry.path);
+
+            if entry.is_dir {
+                fs::create_dir_all(&local_path)?;
+                continue;
+            }
+            if fs::metadata(&local_path).is_ok_and(|metadata| metadata.len() == entry.size) {
+                self.print_colored(
+                    &format!("Skipping file {:?} (already exists with same size)", entry.path),
+                    "cyan",
+                );
+                continue;
+            }
+
+            self.print_colored(
+                &format!("Downloading file {:?} to {:?}", remote_path, local_path),
+                "purple",
+            );
+            if self.download_segmented(remote_path.to_str().unwrap(), &local_path, Some(entry.size))? {
+                continue;
+            }
+            let mut file = File::create(&local_path)?;
+            self.download_to_file(remote_path.to_str().unwrap(), &mut file)?;
+        }
+
+        Ok(())
+    }
+
+    fn sync_remote_dir_to_local(&self, local_dir: &str, remote_dir: &str) -> std::io::Result<()> {
         fs::create_dir_all(local_dir)?;
 
         let (files, dirs) = self.list_files(remote_dir)?;
@@ -465,7 +982,7 @@ impl FtpClient {
                 "purple",
             );
 
-            self.sync_remote_to_local(
+            self.sync_remote_dir_to_local(
                 local_subdir.to_str().unwrap(),
                 remote_subdir.to_str().unwrap(),
             )?;
@@ -474,6 +991,47 @@ impl FtpClient {
         Ok(())
     }
 
+    /// Downloads `remote_path` into `file`, wherever that is locally.
+    fn download_to_file(&self, remote_path: &str, file: &mut File) -> std::io::Result<()> {
+        let mut control_stream = self.connect()?;
+        self.login(&mut control_stream, "testuser")?;
+        let compressed = self.negotiate_compression(&mut control_stream)?;
+        let (data_host, data_port) = self.pasv_mode(&mut control_stream)?;
+
+        let data_stream = loop {
+            match TcpStream::connect((data_host.clone(), data_port)) {
+                Ok(stream) => break stream,
+                Err(e) => {
+                    self.print_colored(
+                        &format!("Failed to connect to data port: {}. Retrying...", e),
+                        "yellow",
+                    );
+                    std::thread::sleep(self.retry_delay);
+                }
+            }
+        };
+
+        control_stream.send(&format!("RETR {}", remote_path))?;
+
+        let reply = control_stream.read_reply()?;
+
+        if reply.code != 150 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "Failed to initiate file download",
+            ));
+        }
+
+        self.receive_data(&mut control_stream, data_stream, file, compressed)?;
+
+        let reply = control_stream.read_reply()?;
+        self.print_colored(
+            &format!("Download of {} completed: {}", remote_path, reply),
+            "blue",
+        );
+        Ok(())
+    }
+
     fn make_remote_dir(&self, remote_dir: &str) -> std::io::Result<()> {
         self.print_colored(
             &format!("Ensuring remote directory {} exists.", remote_dir),
@@ -504,14 +1062,11 @@ impl FtpClient {
         let mut stream = self.connect()?;
         self.login(&mut stream, "testuser")?;
 
-        let command = format!("MKD {}\r\n", remote_dir);
-        stream.write_all(command.as_bytes())?;
+        stream.send(&format!("MKD {}", remote_dir))?;
 
-        let mut response = [0u8; 1024];
-        let n = stream.read(&mut response)?;
-        let response_str = String::from_utf8_lossy(&response[..n]);
+        let reply = stream.read_reply()?;
 
-        if response_str.contains("550") {
+        if reply.code == 550 {
             self.print_colored(
                 &format!(
                     "Remote directory {} already exists or failed to create.",
@@ -519,7 +1074,7 @@ impl FtpClient {
                 ),
                 "yellow",
             );
-        } else if response_str.contains("257") {
+        } else if reply.code == 257 {
             self.print_colored(
                 &format!("Remote directory {} created successfully.", remote_dir),
                 "green",
@@ -538,6 +1093,7 @@ impl FtpClient {
         for attempt in 0..self.max_retries {
             match self.attempt_list_files(remote_dir) {
                 Ok(result) => return Ok(result),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => return Err(e),
                 Err(e) => {
                     if attempt < self.max_retries - 1 {
                         self.print_colored(
@@ -559,28 +1115,20 @@ impl FtpClient {
         let mut control_stream = self.connect()?;
         self.login(&mut control_stream, "testuser")?;
 
-        let command = format!("CWD {}\r\n", remote_dir);
-        control_stream.write_all(command.as_bytes())?;
-
-        let mut response = [0u8; 1024];
-        let n = control_stream.read(&mut response)?;
-        let response_str = String::from_utf8_lossy(&response[..n]);
-        self.print_colored(&format!("Response after CWD: {}", response_str), "yellow");
-
+        let compressed = self.negotiate_compression(&mut control_stream)?;
         let (data_host, data_port) = self.pasv_mode(&mut control_stream)?;
-        let mut data_stream = TcpStream::connect((data_host, data_port))?;
+        let data_stream = TcpStream::connect((data_host, data_port))?;
 
-        control_stream.write_all(b"LIST\r\n")?;
-        let mut response = [0u8; 1024];
-        let n = control_stream.read(&mut response)?;
-        drop(control_stream);
-        let response_str = String::from_utf8_lossy(&response[..n]);
-        self.print_colored(&format!("Response after LIST: {}", response_str), "yellow");
+        control_stream.send(&format!("LIST -a {}", remote_dir))?;
+        let reply = control_stream.read_reply()?;
+        self.print_colored(&format!("Response after LIST: {}", reply), "yellow");
 
         println!("Directory listing received");
 
-        let mut listing = String::new();
-        BufReader::new(&mut data_stream).read_to_string(&mut listing)?;
+        let mut listing = Vec::new();
+        self.receive_data(&mut control_stream, data_stream, &mut listing, compressed)?;
+        drop(control_stream);
+        let listing = String::from_utf8_lossy(&listing);
 
         println!("{}", listing);
 
@@ -604,4 +1152,96 @@ impl FtpClient {
 
         Ok((files, dirs))
     }
+
+    /// Lists everything under `remote_dir` with a single `LIST -R`, or returns `None`
+    /// when the server doesn't advertise it.
+    fn list_tree(&self, remote_dir: &str) -> std::io::Result<Option<Vec<RemoteEntry>>> {
+        self.print_colored(
+            &format!("Listing remote tree: {}", remote_dir),
+            "purple",
+        );
+
+        for attempt in 0..self.max_retries {
+            match self.attempt_list_tree(remote_dir) {
+                Ok(result) => return Ok(result),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => return Err(e),
+                Err(e) => {
+                    if attempt < self.max_retries - 1 {
+                        self.print_colored(
+                            &format!("Failed to list tree: {}. Retrying...", e),
+                            "yellow",
+                        );
+                        std::thread::sleep(self.retry_delay);
+                    } else {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+
+        unreachable!()
+    }
+
+    fn attempt_list_tree(&self, remote_dir: &str) -> std::io::Result<Option<Vec<RemoteEntry>>> {
+        let mut control_stream = self.connect()?;
+        self.login(&mut control_stream, "testuser")?;
+        if !self.features(&mut control_stream)?.iter().any(|feature| feature == "LIST -R") {
+            return Ok(None);
+        }
+
+        let compressed = self.negotiate_compression(&mut control_stream)?;
+        let (data_host, data_port) = self.pasv_mode(&mut control_stream)?;
+
+        let data_stream = loop {
+            match TcpStream::connect((data_host.clone(), data_port)) {
+                Ok(stream) => break stream,
+                Err(e) => {
+                    self.print_colored(
+                        &format!("Failed to connect to data port: {}. Retrying...", e),
+                        "yellow",
+                    );
+                    std::thread::sleep(self.retry_delay);
+                }
+            }
+        };
+
+        control_stream.send(&format!("LIST -Ra {}", remote_dir))?;
+        let reply = control_stream.read_reply()?;
+        self.print_colored(&format!("Response after LIST -R: {}", reply), "yellow");
+        if reply.code / 100 != 1 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "Failed to list remote tree",
+            ));
+        }
+
+        let mut listing = Vec::new();
+        self.receive_data(&mut control_stream, data_stream, &mut listing, compressed)?;
+        let reply = control_stream.read_reply()?;
+        if reply.code != 226 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("Remote tree listing failed: {}", reply),
+            ));
+        }
+
+        // TYPE, size, mtime, hash and relative path, tab separated.
+        let mut tree = Vec::new();
+        for line in String::from_utf8_lossy(&listing).lines() {
+            let parts: Vec<&str> = line.splitn(5, '\t').collect();
+            if parts.len() != 5 {
+                continue;
+            }
+            let Ok(size) = parts[1].parse::<u64>() else {
+                continue;
+            };
+            match parts[0] {
+                "FILE" => tree.push(RemoteEntry { path: parts[4].to_string(), size, is_dir: false }),
+                "DIR" => tree.push(RemoteEntry { path: parts[4].to_string(), size, is_dir: true }),
+                _ => {}
+            }
+        }
+
+        Ok(Some(tree))
+    }
 }
@@ -1,12 +1,33 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock};
 use crate::codec::FtpClient;
 mod codec;
+mod reply;
 
-pub fn apple_sync(host: String, port: u32, local_dir: String, remote_dir: String) -> bool {
-    let client = FtpClient::new(host.to_string(), port as u16);
+/// Set by `cancel_sync` to abort the sync in progress.
+static CANCEL: LazyLock<Arc<AtomicBool>> = LazyLock::new(|| Arc::new(AtomicBool::new(false)));
+
+/// Syncs `local_dir` with `remote_dir` on the server, logging in with `password` when
+/// the server asks for one.
+pub fn apple_sync(host: String, port: u32, local_dir: String, remote_dir: String, password: Option<String>) -> bool {
+    CANCEL.store(false, Ordering::SeqCst);
+    let mut client = FtpClient::new(host.to_string(), port as u16)
+        .with_compression(6)
+        .with_segments(4)
+        .with_cancel(Arc::clone(&CANCEL));
+    if let Some(password) = password {
+        client = client.with_password(password);
+    }
     if let Err(e) = client.sync(&local_dir, &remote_dir) {
         eprintln!("Error syncing directories: {}", e);
         false;
     }
     true
 }
+
+/// Aborts the transfer in progress of a running `apple_sync`, which then returns.
+pub fn cancel_sync() {
+    CANCEL.store(true, Ordering::SeqCst);
+}
+
 uniffi::include_scaffolding!("ftp_client");
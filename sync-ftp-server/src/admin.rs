@@ -0,0 +1,146 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::state::ServerState;
+
+/// Starts the admin HTTP API on `listen`, in its own thread.
+///
+/// - `GET /sessions`: connected clients, with their current transfer
+/// - `POST /sessions/<id>/kick`: disconnects a client
+/// - `GET /users/disabled`, `POST /users/<name>/disable`, `POST /users/<name>/enable`
+/// - `POST /config/reload`: reloads the config file
+pub fn serve(state: Arc<ServerState>, listen: &str, token: Option<String>) {
+    let listener = match TcpListener::bind(listen) {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("[!] Couldn't start the admin API on {}: {}", listen, e);
+            return;
+        }
+    };
+    println!("[*] Admin API listening on {}", listen);
+    if token.is_none() {
+        println!("[!] The admin API has no token, anyone reaching {} can use it", listen);
+    }
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(e) = handle(&state, token.as_deref(), stream) {
+                println!("[!] Admin API request failed: {}", e);
+            }
+        }
+    });
+}
+
+fn handle(state: &ServerState, token: Option<&str>, mut stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    let mut authorized = token.is_none();
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let (Some(token), Some((name, value))) = (token, header.split_once(':')) {
+            // Compare digests, so the time taken doesn't depend on how much of the token matched.
+            let given = value.trim().strip_prefix("Bearer ").unwrap_or_default();
+            if name.eq_ignore_ascii_case("authorization") && Sha256::digest(given) == Sha256::digest(token) {
+                authorized = true;
+            }
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let raw_path = parts.next().unwrap_or_default();
+    println!("[*] Admin API: {} {}", method, raw_path);
+    let path: Option<Vec<String>> = raw_path.split('/').filter(|part| !part.is_empty()).map(percent_decode).collect();
+
+    let (status, body) = match path {
+        _ if !authorized => (401, json!({ "error": "unauthorized" })),
+        Some(path) => route(state, method, &path.iter().map(String::as_str).collect::<Vec<_>>()),
+        None => (400, json!({ "error": "invalid path" })),
+    };
+
+    let body = body.to_string();
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        _ => "Internal Server Error",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    )
+}
+
+/// Decodes `%XX` escapes in a path segment, so user names can hold any character.
+/// `None` for a malformed escape or a result that isn't UTF-8.
+fn percent_decode(segment: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(segment.len());
+    let mut rest = segment.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = tail.get(..2).filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))?;
+            bytes.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn route(state: &ServerState, method: &str, path: &[&str]) -> (u16, serde_json::Value) {
+    match (method, path) {
+        ("GET", ["sessions"]) => (200, json!(state.sessions.list())),
+        ("POST", ["sessions", id, "kick"]) => match id.parse() {
+            Ok(id) if state.sessions.kick(id) => (200, json!({ "kicked": id })),
+            Ok(_) => (404, json!({ "error": "no such session" })),
+            Err(_) => (400, json!({ "error": "invalid session id" })),
+        },
+        ("GET", ["users", "disabled"]) => (200, json!(state.sessions.disabled())),
+        ("POST", ["users", user, "disable"]) => {
+            let kicked = state.sessions.disable(user);
+            (200, json!({ "disabled": user, "kicked": kicked }))
+        }
+        ("POST", ["users", user, "enable"]) => (200, json!({ "enabled": user, "was_disabled": state.sessions.enable(user) })),
+        ("POST", ["config", "reload"]) => match state.reload_config() {
+            Ok(()) => (200, json!({ "reloaded": true })),
+            Err(e) => (500, json!({ "error": e })),
+        },
+        _ => (404, json!({ "error": "not found" })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_decode_segments() {
+        assert_eq!(percent_decode("bob").as_deref(), Some("bob"));
+        assert_eq!(percent_decode("jane%20doe").as_deref(), Some("jane doe"));
+        assert_eq!(percent_decode("ren%C3%A9").as_deref(), Some("rené"));
+        assert_eq!(percent_decode("100%25").as_deref(), Some("100%"));
+        assert_eq!(percent_decode("bad%2"), None);
+        assert_eq!(percent_decode("bad%zz"), None);
+        assert_eq!(percent_decode("bad%+1"), None);
+        assert_eq!(percent_decode("%FF"), None);
+    }
+}
@@ -0,0 +1,209 @@
+//! Measures upload and download throughput against a running server.
+//!
+//! Usage: bench [address] [--size MiB] [--user name] [--pass password]
+//!
+//! `address` defaults to 127.0.0.1:1234 and `--size` to 256. The file is uploaded with
+//! STOR, downloaded back with RETR, checked, and deleted. When the server moved it to
+//! the trash, it's purged from there too.
+
+use std::env;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result, Write};
+use std::net::TcpStream;
+use std::process::exit;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CHUNK: usize = 1024 * 1024;
+const FILE_NAME: &str = ".bench";
+
+struct Control {
+    reader: BufReader<TcpStream>,
+    host: String,
+}
+
+impl Control {
+    fn connect(address: &str) -> Result<Self> {
+        let stream = TcpStream::connect(address)?;
+        let host = address.rsplit_once(':').map(|(host, _)| host).unwrap_or(address).to_string();
+        let mut control = Control { reader: BufReader::new(stream), host };
+        control.expect(2)?;
+        Ok(control)
+    }
+
+    /// Reads one reply and returns its last line.
+    fn reply(&mut self) -> Result<String> {
+        let mut lines = self.reply_lines()?;
+        Ok(lines.pop().unwrap_or_default())
+    }
+
+    /// Reads one reply and returns all its lines.
+    fn reply_lines(&mut self) -> Result<Vec<String>> {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed by server"));
+            }
+            let line = line.trim_end().to_string();
+            let last = line.as_bytes().get(3) != Some(&b'-') && line.len() >= 3 && line.as_bytes()[..3].iter().all(u8::is_ascii_digit);
+            lines.push(line);
+            if last {
+                return Ok(lines);
+            }
+        }
+    }
+
+    /// Reads one reply and fails unless its code starts with `class`.
+    fn expect(&mut self, class: u8) -> Result<String> {
+        let line = self.reply()?;
+        if line.as_bytes()[0] != b'0' + class {
+            return Err(Error::other(format!("Unexpected reply: {}", line)));
+        }
+        Ok(line)
+    }
+
+    fn send(&mut self, command: &str) -> Result<()> {
+        self.reader.get_mut().write_all(format!("{}\r\n", command).as_bytes())
+    }
+
+    fn pasv(&mut self) -> Result<TcpStream> {
+        self.send("PASV")?;
+        let reply = self.expect(2)?;
+        let numbers: Vec<u16> = reply
+            .find('(')
+            .zip(reply.find(')'))
+            .map(|(start, end)| reply[start + 1..end].split(',').filter_map(|n| n.trim().parse().ok()).collect())
+            .unwrap_or_default();
+        if numbers.len() != 6 {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Bad PASV reply: {}", reply)));
+        }
+        let address = format!("{}:{}", self.host, numbers[4] * 256 + numbers[5]);
+        // The server may still be setting up its listener when the reply arrives.
+        for _ in 0..20 {
+            if let Ok(stream) = TcpStream::connect(&address) {
+                return Ok(stream);
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        TcpStream::connect(&address)
+    }
+}
+
+/// Deterministic filler that doesn't compress or dedup away.
+fn pattern(seed: u64) -> Vec<u8> {
+    let mut state = seed | 1;
+    let mut chunk = Vec::with_capacity(CHUNK);
+    while chunk.len() < CHUNK {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        chunk.extend_from_slice(&state.to_le_bytes());
+    }
+    chunk
+}
+
+fn rate(bytes: u64, elapsed: Duration) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64()
+}
+
+fn bench(address: &str, size: u64, user: &str, pass: Option<&str>) -> Result<()> {
+    let mut control = Control::connect(address)?;
+    control.send(&format!("USER {}", user))?;
+    if control.reply()?.starts_with("331") {
+        control.send(&format!("PASS {}", pass.unwrap_or_default()))?;
+        control.expect(2)?;
+    }
+    let chunk = pattern(size);
+    let total = size * CHUNK as u64;
+
+    let mut data = control.pasv()?;
+    control.send(&format!("STOR {}", FILE_NAME))?;
+    control.expect(1)?;
+    let start = Instant::now();
+    for _ in 0..size {
+        data.write_all(&chunk)?;
+    }
+    drop(data);
+    control.expect(2)?;
+    let elapsed = start.elapsed();
+    println!("STOR {} MiB in {:.2}s: {:.1} MiB/s", size, elapsed.as_secs_f64(), rate(total, elapsed));
+
+    let mut data = control.pasv()?;
+    control.send(&format!("RETR {}", FILE_NAME))?;
+    control.expect(1)?;
+    let start = Instant::now();
+    let mut buffer = vec![0u8; CHUNK];
+    let mut received = 0u64;
+    let mut matches = true;
+    loop {
+        let n = data.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        let offset = (received % CHUNK as u64) as usize;
+        let head = n.min(CHUNK - offset);
+        matches &= buffer[..head] == chunk[offset..offset + head] && buffer[head..n] == chunk[..n - head];
+        received += n as u64;
+    }
+    control.expect(2)?;
+    let elapsed = start.elapsed();
+    println!("RETR {} MiB in {:.2}s: {:.1} MiB/s", size, elapsed.as_secs_f64(), rate(received, elapsed));
+
+    control.send(&format!("DELE {}", FILE_NAME))?;
+    control.expect(2)?;
+    purge_from_trash(&mut control)?;
+    if received != total || !matches {
+        return Err(Error::new(ErrorKind::InvalidData, "Downloaded data doesn't match the upload"));
+    }
+    Ok(())
+}
+
+/// Purges the test file from the trash, so runs don't pile up there. The newest entry
+/// for it is the one just deleted.
+fn purge_from_trash(control: &mut Control) -> Result<()> {
+    control.send("SITE TRASH LIST")?;
+    let lines = control.reply_lines()?;
+    if !lines.last().is_some_and(|line| line.starts_with('2')) {
+        return Ok(());
+    }
+    let path = format!("/{}", FILE_NAME);
+    let newest = lines
+        .iter()
+        .filter_map(|line| {
+            let mut fields = line.trim_start().split('\t');
+            let id = fields.next()?;
+            (fields.nth(1)? == path).then_some(id)
+        })
+        .max();
+    if let Some(id) = newest {
+        control.send(&format!("SITE TRASH PURGE {}", id))?;
+        control.expect(2)?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let mut address = "127.0.0.1:1234".to_string();
+    let mut size = 256;
+    let mut user = "bench".to_string();
+    let mut pass = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--size" => size = args.next().and_then(|size| size.parse().ok()).unwrap_or(0),
+            "--user" => user = args.next().unwrap_or_default(),
+            "--pass" => pass = args.next(),
+            _ if !arg.starts_with("--") => address = arg,
+            _ => size = 0,
+        }
+    }
+    if size == 0 {
+        eprintln!("Usage: bench [address] [--size MiB] [--user name] [--pass password]");
+        exit(2);
+    }
+
+    if let Err(e) = bench(&address, size, &user, pass.as_deref()) {
+        eprintln!("Benchmark failed: {}", e);
+        exit(1);
+    }
+}
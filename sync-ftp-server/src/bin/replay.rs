@@ -0,0 +1,290 @@
+//! Drives a server with a session recorded by the server's recorder, and reports the
+//! replies that differ from the recording.
+//!
+//! Usage: replay <recording> [address] [--timing] [--password=<password>]
+//!
+//! `address` defaults to 127.0.0.1:1234. With `--timing`, commands are sent with the
+//! recorded delays. Only the control channel is recorded: uploads are replayed as empty
+//! files, and downloaded data is discarded. Passwords are recorded as `PASS ****`, which
+//! is sent as is unless `--password` gives the one to use instead.
+//!
+//! Replies are compared with data ports, timestamps, trash and version ids and SITE WAIT
+//! cursors masked, as they differ from run to run. Ids and cursors the server replied
+//! with are substituted in later commands.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{Shutdown, TcpStream};
+use std::process::exit;
+use std::thread;
+use std::time::Duration;
+
+/// A recorded command, with the reply lines the server sent back.
+struct Step {
+    at: u64,
+    command: String,
+    replies: Vec<String>,
+}
+
+fn parse(recording: &str) -> (Vec<String>, Vec<Step>) {
+    let mut greeting = Vec::new();
+    let mut steps: Vec<Step> = Vec::new();
+    for line in recording.lines() {
+        if line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.splitn(3, ' ');
+        let at = parts.next().and_then(|at| at.parse().ok()).unwrap_or(0);
+        let direction = parts.next().unwrap_or_default();
+        let text = parts.next().unwrap_or_default().to_string();
+        match (direction, steps.last_mut()) {
+            ("C", _) => steps.push(Step { at, command: text, replies: Vec::new() }),
+            ("S", Some(step)) => step.replies.push(text),
+            ("S", None) => greeting.push(text),
+            _ => {}
+        }
+    }
+    (greeting, steps)
+}
+
+/// Number of complete replies in `lines`, multi-line replies counting once.
+fn count_replies(lines: &[String]) -> usize {
+    let mut count = 0;
+    let mut open: Option<&str> = None;
+    for line in lines {
+        match open {
+            Some(code) => {
+                if line.len() >= 4 && line.starts_with(code) && line.as_bytes()[3] == b' ' {
+                    open = None;
+                    count += 1;
+                }
+            }
+            None if line.as_bytes().get(3) == Some(&b'-') => open = Some(&line[..3]),
+            None => count += 1,
+        }
+    }
+    count
+}
+
+struct Control {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+}
+
+impl Control {
+    fn read_line(&mut self) -> Result<String> {
+        loop {
+            if let Some(end) = self.buffer.windows(2).position(|w| w == b"\r\n") {
+                let line = String::from_utf8_lossy(&self.buffer[..end]).to_string();
+                self.buffer.drain(..end + 2);
+                return Ok(line);
+            }
+            let mut chunk = [0u8; 1024];
+            let n = self.stream.read(&mut chunk)?;
+            if n == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed by server"));
+            }
+            self.buffer.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    /// Reads one reply, returning all its lines.
+    fn read_reply(&mut self) -> Result<Vec<String>> {
+        let first = self.read_line()?;
+        let mut lines = vec![first.clone()];
+        if first.as_bytes().get(3) == Some(&b'-') {
+            loop {
+                let line = self.read_line()?;
+                let last = line.len() >= 4 && line.starts_with(&first[..3]) && line.as_bytes()[3] == b' ';
+                lines.push(line);
+                if last {
+                    break;
+                }
+            }
+        }
+        Ok(lines)
+    }
+
+    fn read_replies(&mut self, count: usize) -> Result<Vec<String>> {
+        let mut lines = Vec::new();
+        for _ in 0..count {
+            lines.extend(self.read_reply()?);
+        }
+        Ok(lines)
+    }
+}
+
+/// Data port from a `227 Entering Passive Mode (h1,h2,h3,h4,p1,p2)` reply.
+fn pasv_address(reply: &str, host: &str) -> Option<String> {
+    let numbers: Vec<u16> = reply[reply.find('(')? + 1..reply.find(')')?]
+        .split(',')
+        .filter_map(|n| n.trim().parse().ok())
+        .collect();
+    if numbers.len() != 6 {
+        return None;
+    }
+    Some(format!("{}:{}", host, numbers[4] * 256 + numbers[5]))
+}
+
+/// Whether a run of digits and dashes changes between runs: a Unix time in seconds or
+/// nanoseconds (timestamps, trash and version ids), or a `<boot>-<seq>` change cursor.
+fn volatile(token: &str) -> bool {
+    let (head, tail) = token.split_once('-').unwrap_or((token, "0"));
+    head.len() >= 10 && head.bytes().all(|b| b.is_ascii_digit()) && !tail.is_empty() && tail.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Splits `line` into runs of digits and dashes, and the text between them.
+fn tokens(line: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    let mut numeric = false;
+    for (i, c) in line.char_indices() {
+        let digit = c.is_ascii_digit() || c == '-';
+        if i > start && digit != numeric {
+            tokens.push(&line[start..i]);
+            start = i;
+        }
+        numeric = digit;
+    }
+    if start < line.len() {
+        tokens.push(&line[start..]);
+    }
+    tokens
+}
+
+/// `line` with what differs between runs replaced by `*`.
+fn mask(line: &str) -> String {
+    if let (true, Some(open), Some(close)) = (line.starts_with("227"), line.find('('), line.rfind(')')) {
+        // Keep the host, not the port bytes.
+        let host: Vec<&str> = line[open + 1..close].split(',').take(4).collect();
+        return format!("{}({},*,*){}", &line[..open], host.join(","), &line[close + 1..]);
+    }
+    tokens(line).into_iter().map(|token| if volatile(token) { "*" } else { token }).collect()
+}
+
+/// Remembers which recorded ids and cursors the server now knows under other values.
+fn learn(expected: &[String], actual: &[String], substitutes: &mut HashMap<String, String>) {
+    for (expected, actual) in expected.iter().zip(actual) {
+        let recorded = tokens(expected).into_iter().filter(|token| volatile(token));
+        let current = tokens(actual).into_iter().filter(|token| volatile(token));
+        for (recorded, current) in recorded.zip(current) {
+            substitutes.insert(recorded.to_string(), current.to_string());
+        }
+    }
+}
+
+fn compare(label: &str, expected: &[String], actual: &[String]) -> bool {
+    if expected.len() == actual.len() && expected.iter().zip(actual).all(|(expected, actual)| mask(expected) == mask(actual)) {
+        return true;
+    }
+    println!("!= {}", label);
+    for line in expected {
+        println!("   - {}", line);
+    }
+    for line in actual {
+        println!("   + {}", line);
+    }
+    false
+}
+
+fn replay(path: &str, address: &str, timing: bool, password: Option<&str>) -> Result<usize> {
+    let recording = fs::read_to_string(path)?;
+    let (greeting, steps) = parse(&recording);
+    let host = address.rsplit_once(':').map(|(host, _)| host).unwrap_or(address).to_string();
+
+    let mut control = Control {
+        stream: TcpStream::connect(address)?,
+        buffer: Vec::new(),
+    };
+    control.stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+
+    let mut differences = 0;
+    let actual = control.read_replies(count_replies(&greeting))?;
+    if !compare("greeting", &greeting, &actual) {
+        differences += 1;
+    }
+
+    let mut data: Option<TcpStream> = None;
+    let mut substitutes = HashMap::new();
+    let mut last_at = 0;
+    for step in &steps {
+        if timing {
+            thread::sleep(Duration::from_millis(step.at.saturating_sub(last_at)));
+        }
+        last_at = step.at;
+
+        let command = match password {
+            Some(password) if step.command == "PASS ****" => format!("PASS {}", password),
+            _ => tokens(&step.command)
+                .into_iter()
+                .map(|token| substitutes.get(token).map(String::as_str).unwrap_or(token))
+                .collect(),
+        };
+        println!("-> {}", step.command);
+        control.stream.write_all(format!("{}\r\n", command).as_bytes())?;
+        let verb = step.command.split(' ').next().unwrap_or_default().to_ascii_uppercase();
+        let expected = count_replies(&step.replies);
+
+        let mut actual = Vec::new();
+        if verb == "PASV" {
+            // The server waits for the data connection before reading the next command.
+            let reply = control.read_reply()?;
+            if let Some(address) = reply.first().and_then(|line| pasv_address(line, &host)) {
+                // It may still be setting up its listener when the reply arrives.
+                for _ in 0..20 {
+                    data = TcpStream::connect(&address).ok();
+                    if data.is_some() {
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(50));
+                }
+            }
+            actual.extend(reply);
+            actual.extend(control.read_replies(expected.saturating_sub(1))?);
+        } else if let (Some(stream), "STOR" | "RETR" | "LIST") = (data.take(), verb.as_str()) {
+            if verb == "STOR" {
+                // Uploaded data isn't recorded: send an empty file.
+                let _ = stream.shutdown(Shutdown::Write);
+            }
+            let mut drain = stream;
+            thread::spawn(move || {
+                let _ = std::io::copy(&mut drain, &mut std::io::sink());
+            });
+            actual = control.read_replies(expected)?;
+        } else {
+            actual = control.read_replies(expected)?;
+        }
+
+        learn(&step.replies, &actual, &mut substitutes);
+        if !compare(&step.command, &step.replies, &actual) {
+            differences += 1;
+        }
+    }
+    Ok(differences)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let timing = args.iter().any(|arg| arg == "--timing");
+    let password = args.iter().find_map(|arg| arg.strip_prefix("--password="));
+    let positional: Vec<&String> = args.iter().filter(|arg| !arg.starts_with("--")).collect();
+    let Some(path) = positional.first() else {
+        eprintln!("Usage: replay <recording> [address] [--timing] [--password=<password>]");
+        exit(2);
+    };
+    let address = positional.get(1).map(|address| address.as_str()).unwrap_or("127.0.0.1:1234");
+
+    match replay(path, address, timing, password) {
+        Ok(0) => println!("Replay matched the recording"),
+        Ok(differences) => {
+            println!("{} step(s) differ from the recording", differences);
+            exit(1);
+        }
+        Err(e) => {
+            eprintln!("Replay failed: {}", e);
+            exit(2);
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Changes remembered for SITE WAIT. Clients further behind have to sync in full.
+const HISTORY: usize = 4096;
+/// Longest SITE WAIT, so sessions of vanished clients don't block for long.
+pub const MAX_WAIT: Duration = Duration::from_secs(300);
+
+/// Feed of changed paths that sessions can block on, for SITE WAIT.
+///
+/// Cursors are `<boot>-<seq>`: the server start time and the sequence number of the
+/// next change, so a cursor from before a restart is recognized as stale.
+pub struct Changes {
+    boot: u64,
+    log: Mutex<Log>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct Log {
+    next: u64,
+    recent: VecDeque<(u64, PathBuf)>,
+}
+
+/// How a wait ended.
+pub enum Wait {
+    /// Paths changed after the cursor, oldest first and without duplicates.
+    Changed(Vec<PathBuf>),
+    TimedOut,
+    /// The cursor is from another run of the server or too old: everything may have changed.
+    Resync,
+}
+
+impl Changes {
+    pub fn new() -> Changes {
+        Changes {
+            boot: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            log: Mutex::new(Log::default()),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub fn record(&self, path: &Path) {
+        let mut log = self.log.lock().unwrap_or_else(PoisonError::into_inner);
+        let seq = log.next;
+        log.next += 1;
+        log.recent.push_back((seq, path.to_path_buf()));
+        if log.recent.len() > HISTORY {
+            log.recent.pop_front();
+        }
+        self.changed.notify_all();
+    }
+
+    /// Waits until a path passing `visible` changes after `cursor`, or until `timeout`.
+    /// Returns the cursor to wait from next, and what happened.
+    pub fn wait(&self, cursor: &str, timeout: Duration, visible: impl Fn(&Path) -> bool) -> (String, Wait) {
+        let deadline = Instant::now() + timeout;
+        let mut log = self.log.lock().unwrap_or_else(PoisonError::into_inner);
+        let from = cursor
+            .split_once('-')
+            .filter(|(boot, _)| boot.parse() == Ok(self.boot))
+            .and_then(|(_, seq)| seq.parse::<u64>().ok())
+            .filter(|&seq| seq <= log.next && log.recent.front().is_none_or(|&(oldest, _)| seq >= oldest));
+        let Some(from) = from else {
+            return (self.cursor(log.next), Wait::Resync);
+        };
+
+        loop {
+            let mut paths: Vec<PathBuf> = Vec::new();
+            for (_, path) in log.recent.iter().filter(|(seq, _)| *seq >= from) {
+                if visible(path) && !paths.contains(path) {
+                    paths.push(path.clone());
+                }
+            }
+            if !paths.is_empty() {
+                return (self.cursor(log.next), Wait::Changed(paths));
+            }
+            let Some(left) = deadline.checked_duration_since(Instant::now()).filter(|left| !left.is_zero()) else {
+                return (self.cursor(log.next), Wait::TimedOut);
+            };
+            log = self.changed.wait_timeout(log, left).unwrap_or_else(PoisonError::into_inner).0;
+        }
+    }
+
+    fn cursor(&self, seq: u64) -> String {
+        format!("{}-{}", self.boot, seq)
+    }
+}
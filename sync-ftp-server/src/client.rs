@@ -1,153 +1,275 @@
-use std::net::{TcpStream, TcpListener, SocketAddr, IpAddr, Ipv4Addr};
-use std::path::PathBuf;
-use std::fs::{create_dir, remove_dir_all, File, OpenOptions, read_dir};
-use std::io::{Read, Write, ErrorKind};
-use std::env;
+use std::net::{TcpStream, TcpListener, SocketAddr, IpAddr, Ipv4Addr, Shutdown, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write, ErrorKind};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
 
-use crate::command::{Command, ResultCode};
-use crate::utils::{send_cmd, read_all_message};
+use sha2::{Digest, Sha256};
+
+use crate::changes::{self, Wait};
+use crate::command::{Command, ResultCode, SUPPORTED_COMMANDS};
+use crate::config::{Config, META_DIR};
+use crate::error::{self, FtpError};
+use crate::find::{self, FindQuery};
+use crate::hooks::{self, Event, EventKind};
+use crate::jail::{virtual_path, PathError};
+use crate::recorder::{Recorder, RecordingStream};
+use crate::sessions::{GuestPass, Registration};
+use crate::state::ServerState;
+use crate::storage::{self, Entry, Storage};
+use crate::transfer::{self, AbortWatch, DataReader, DataWriter, Throttle, TransferMode, BUFFER_SIZE};
+use crate::trash::Trash;
+use crate::versions::Versions;
+use crate::utils::{send_cmd, send_reply, strip_telnet, Line, LineReader, Reply};
+
+/// Most matches SITE FIND replies with, as they all go over the control connection.
+const MAX_FOUND: usize = 1000;
 
 pub struct Client {
     cwd: PathBuf,
-    stream: TcpStream,
+    stream: RecordingStream,
+    lines: LineReader,
     name: Option<String>,
     data_writer: Option<TcpStream>,
+    mode: TransferMode,
+    /// User given to USER, waiting for PASS.
+    pending_user: Option<String>,
+    /// Source of a rename, set by RNFR and consumed by RNTO.
+    rename_from: Option<PathBuf>,
+    /// Offset set by REST, where the next RETR or STOR starts.
+    restart: Option<u64>,
+    /// Size announced by ALLO for the next STOR.
+    allocated: Option<u64>,
+    /// Set while logged in as the anonymous guest.
+    guest: Option<GuestPass>,
+    storage: Arc<dyn Storage>,
+    /// Config as of the current command; a reload is picked up by the next one.
+    config: Arc<Config>,
+    state: Arc<ServerState>,
+    session: Registration,
 }
 
 impl Client {
-    pub fn new(stream: TcpStream) -> Client {
+    pub fn new(stream: TcpStream, state: Arc<ServerState>) -> Client {
+        let session = state.sessions.register(&stream);
+        let config = state.config();
+        let recorder = if config.recording.enabled {
+            Recorder::create(&config.recording.dir, session.id, &stream)
+                .map_err(|e| println!("[!] Couldn't record session {}: {}", session.id, e))
+                .ok()
+        } else {
+            None
+        };
+
         Client {
+            session,
             cwd: PathBuf::from("/"),
-            stream,
+            stream: RecordingStream::new(stream, recorder),
+            lines: LineReader::default(),
             name: None,
             data_writer: None,
+            mode: TransferMode::Stream,
+            pending_user: None,
+            rename_from: None,
+            restart: None,
+            allocated: None,
+            guest: None,
+            storage: Arc::clone(&state.storage),
+            config,
+            state,
         }
     }
 
-    pub fn handle_client(mut stream: TcpStream) {
+    pub fn handle_client(stream: TcpStream, state: Arc<ServerState>) {
         println!("[+] New client connected!");
-        send_cmd(&mut stream, ResultCode::ServiceReadyForNewUser, "Welcome to this FTP server!");
+        let mut client = Client::new(stream, state);
+        send_cmd(&mut client.stream, ResultCode::ServiceReadyForNewUser, "Welcome to this FTP server!");
 
-        let mut client = Client::new(stream);
         loop {
-            let data = read_all_message(&mut client.stream);
+            let data = match client.lines.read_line(&mut client.stream) {
+                Line::Command(data) => data,
+                Line::TooLong => {
+                    send_cmd(&mut client.stream, ResultCode::UnknownCommand, "Command line too long.");
+                    continue;
+                }
+                Line::Closed => {
+                    println!("[+] Client disconnected...");
+                    break;
+                }
+            };
+            let data = strip_telnet(&data);
             if data.is_empty() {
-                println!("[+] Client disconnected...");
-                break;
+                continue;
             }
+            client.stream.record_command(&data);
 
-            client.handle_cmd(Command::new(data).unwrap());
+            client.config = client.state.config();
+            client.handle_cmd(Command::new(data));
         }
     }
 
-    fn complete_path(&self, path: PathBuf, server_root: &PathBuf) -> Result<PathBuf, std::io::Error> {
-        let directory = server_root.join(if path.has_root() {
-            path.iter().skip(1).collect()
-        } else {
-            path
-        });
+    /// Virtual path of a client-supplied `path`, relative to the working directory.
+    fn path(&self, path: &Path) -> Result<PathBuf, PathError> {
+        let path = virtual_path(&self.cwd, path)?;
+        if !self.may_access(&path) {
+            return Err(PathError::Hidden);
+        }
+        Ok(path)
+    }
 
-        if let Ok(ref dir) = directory.canonicalize() {
-            let dir = dir.to_str().unwrap();
-            if !dir.starts_with(server_root.to_str().unwrap()) {
-                return Err(std::io::Error::new(ErrorKind::PermissionDenied, "Permission denied"));
-            }
+    /// Whether the session may reach `path`: guests only their share, users the shares
+    /// open to them.
+    fn may_access(&self, path: &Path) -> bool {
+        match self.guest {
+            Some(_) => path.starts_with(Path::new("/").join(&self.config.anonymous.share)),
+            None => self.config.may_access(self.user(), path),
         }
-        Ok(directory)
     }
 
     fn cwd(&mut self, directory: PathBuf) {
-        let server_root = env::current_dir().unwrap();
-        let path = self.cwd.join(&directory);
-        if let Ok(dir) = self.complete_path(path, &server_root) {
-            if let Ok(prefix) = dir.strip_prefix(&server_root).map(|p| p.to_path_buf()) {
-                self.cwd = prefix;
-                send_cmd(&mut self.stream, ResultCode::Ok, &format!("Directory changed to \"{}\"", directory.display()));
+        let path = match self.path(&directory) {
+            Ok(path) => path,
+            Err(e) => {
+                send_cmd(&mut self.stream, ResultCode::FileUnavailable, &e.to_string());
                 return;
             }
+        };
+        match self.storage.stat(&path) {
+            Ok(metadata) if metadata.is_dir => {
+                self.session.update(|info| info.cwd = path.clone());
+                self.cwd = path;
+                send_cmd(&mut self.stream, ResultCode::Ok, &format!("Directory changed to \"{}\"", directory.display()));
+            }
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => send_cmd(&mut self.stream, ResultCode::FileUnavailable, &e.to_string()),
+            _ => send_cmd(&mut self.stream, ResultCode::InvalidParameterOrArgument, "No such file or directory"),
         }
-
-        send_cmd(&mut self.stream, ResultCode::InvalidParameterOrArgument, "No such file or directory");
     }
 
     fn handle_cmd(&mut self, cmd: Command) {
-        println!("{:?}", cmd);
+        match &cmd {
+            Command::Pass(_) => println!("Pass(\"****\")"),
+            cmd => println!("{:?}", cmd),
+        }
+
+        let open = matches!(cmd, Command::User(_) | Command::Pass(_) | Command::Auth | Command::Syst | Command::Feat | Command::Help);
+        if !open && self.name.is_none() && !self.config.users.is_empty() {
+            send_cmd(&mut self.stream, ResultCode::NotLoggedIn, "Please login with USER and PASS");
+            return;
+        }
+        let writes = match &cmd {
+            Command::Stor(_) | Command::Mkdir(_) | Command::Rmd(_) | Command::Dele(_) | Command::Rnfr(_) | Command::Rnto(_) => true,
+            Command::Site(args) => !read_only_site(args),
+            _ => false,
+        };
+        if writes && self.guest.is_some() {
+            send_cmd(&mut self.stream, ResultCode::FileUnavailable, "Permission denied (anonymous access is read-only)");
+            return;
+        }
+
+        let name = cmd.as_ref().to_string();
+        if let Err(e) = self.run(cmd) {
+            println!("[!] {} failed: {}", name, e);
+            send_cmd(&mut self.stream, e.code(), &e.to_string());
+        }
+    }
+
+    /// Runs a command. Failures that return an error are answered by `handle_cmd`.
+    fn run(&mut self, cmd: Command) -> error::Result<()> {
         match cmd {
-            Command::Stor(path) => self.stor(path),
-            Command::Retr(path) => self.retr(path),
+            Command::Stor(path) => {
+                let result = self.stor(path);
+                self.end_transfer();
+                result?
+            }
+            Command::Retr(path) => {
+                let result = self.retr(path);
+                self.end_transfer();
+                result?
+            }
+            Command::Abor => {
+                // An aborted transfer already got its 426; otherwise there was nothing to abort.
+                self.end_transfer();
+                send_cmd(&mut self.stream, ResultCode::ClosingDataConnection, "Abort successful.");
+            }
             Command::Auth => send_cmd(&mut self.stream, ResultCode::CommandNotImplemented, "Not implemented"),
             Command::Syst => send_cmd(&mut self.stream, ResultCode::Ok, "UNIX Type: L8"),
             Command::User(username) => {
                 if username.is_empty() {
                     send_cmd(&mut self.stream, ResultCode::InvalidParameterOrArgument, "Invalid username");
+                } else if self.state.sessions.is_disabled(&username) {
+                    send_cmd(&mut self.stream, ResultCode::NotLoggedIn, "User disabled");
+                } else if self.config.anonymous.enabled && is_guest_name(&username) {
+                    self.name = None;
+                    self.guest = None;
+                    send_cmd(&mut self.stream, ResultCode::NeedAccountForLogin, "Anonymous login ok, send your email address as password");
+                    self.pending_user = Some(username);
+                } else if self.config.users.is_empty() {
+                    self.guest = None;
+                    self.log_in(username);
                 } else {
-                    self.name = Some(username);
-                    send_cmd(&mut self.stream, ResultCode::UserLoggedIn, &format!("Welcome {}", self.name.as_ref().unwrap()));
+                    self.name = None;
+                    self.guest = None;
+                    send_cmd(&mut self.stream, ResultCode::NeedAccountForLogin, &format!("Password required for {}", username));
+                    self.pending_user = Some(username);
                 }
             },
+            Command::Pass(password) => self.pass(password),
             Command::Pwd => {
                 let msg = format!("\"{}\"", self.cwd.to_str().unwrap_or(""));
                 if !msg.is_empty() {
-                    send_cmd(&mut self.stream, ResultCode::PATHNAMECreated, &format!("{}", msg));
+                    send_cmd(&mut self.stream, ResultCode::PATHNAMECreated, &msg);
                 } else {
                     send_cmd(&mut self.stream, ResultCode::InvalidParameterOrArgument, "No such file or directory");
                 }
             },
             Command::Type => send_cmd(&mut self.stream, ResultCode::Ok, "Switching to Binary mode."),
-            Command::List(_) => {
-                self.list()
-            },
-            Command::Pasv => {
-                if self.data_writer.is_some() {
-                    send_cmd(&mut self.stream, ResultCode::FileStatusOk, "Already listening...");
-                } else {
-                    let port = 43210; // Or choose a random available port
-
-                    // Get local IP
-                    let local_ip = self.stream.local_addr().unwrap().ip();
-
-                    // Calculate p1 and p2 for the PASV response (address is hardcoded as 127,0,0,1)
-                    let p1 = port / 256;
-                    let p2 = port % 256;
-
-                    let ip_parts: Vec<u8> = match local_ip {
-                        IpAddr::V4(ip) => ip.octets().to_vec(),
-                        _ => vec![127, 0, 0, 1], // Fallback to localhost if not IPv4
-                    };
-
-                    send_cmd(
-                        &mut self.stream,
-                        ResultCode::EnteringPassiveMode,
-                        &format!("Entering Passive Mode ({},{},{},{},{},{})",
-                                 ip_parts[0], ip_parts[1], ip_parts[2], ip_parts[3], p1, p2),
-                    );
-                    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port); // Bind to all interfaces
-                    let listener = match TcpListener::bind(&addr) {
-                        Ok(listener) => listener,
-                        Err(e) => {
-                            println!("Error binding to data port: {}", e);
-                            send_cmd(&mut self.stream, ResultCode::CantOpenDataConnection, "Failed to open data connection.");
-                            return;
-                        }
-                    };
-
-                    match listener.incoming().next() {
-                        Some(Ok(client)) => {
-                            self.data_writer = Some(client);
-                        },
-                        Some(Err(e)) => {
-                            println!("Error accepting data connection: {}", e);
-                            send_cmd(&mut self.stream, ResultCode::CantOpenDataConnection, "Failed to open data connection.");
-                            return;
-                        }
-                        None => {
-                            println!("No incoming data connection.");
-                            send_cmd(&mut self.stream, ResultCode::CantOpenDataConnection, "Failed to open data connection.");
-                            return;
-                        }
-                    }
+            Command::Mode(mode) => self.mode(mode),
+            Command::Feat => {
+                let mut reply = Reply::new(ResultCode::SystemStatus, "Features:");
+                if self.config.compression.enabled {
+                    reply.push("MODE Z");
                 }
-            },
+                reply.push("LIST -R");
+                reply.push("REST STREAM");
+                reply.push("SIZE");
+                reply.push("HASH SHA-256");
+                reply.push("AVBL");
+                reply.push("End");
+                send_reply(&mut self.stream, &reply);
+            }
+            Command::Help => {
+                let mut reply = Reply::new(ResultCode::HelpMessage, "The following commands are recognized:");
+                for commands in SUPPORTED_COMMANDS.chunks(8) {
+                    reply.push(commands.join(" "));
+                }
+                reply.push("Help OK.");
+                send_reply(&mut self.stream, &reply);
+            }
+            Command::Stat(None) => {
+                let mut reply = Reply::new(ResultCode::SystemStatus, "Ventus FTP server status:");
+                reply.push(format!("Logged in as {}", self.name.as_deref().unwrap_or("nobody")));
+                reply.push(format!("Working directory \"{}\"", self.cwd.display()));
+                reply.push(match self.mode {
+                    TransferMode::Stream => "Transfer mode STREAM".to_string(),
+                    TransferMode::Deflate(level) => format!("Transfer mode DEFLATE, level {}", level),
+                });
+                reply.push(format!("Data connection {}", if self.data_writer.is_some() { "open" } else { "closed" }));
+                reply.push("End of status");
+                send_reply(&mut self.stream, &reply);
+            }
+            Command::Stat(Some(_)) => {
+                send_cmd(&mut self.stream, ResultCode::CommandNotImplementedForThatParameter, "STAT with a path is not supported");
+            }
+            Command::List(arg) => {
+                self.list(arg, false);
+                self.end_transfer();
+            }
+            Command::Nlst(arg) => {
+                self.list(arg, true);
+                self.end_transfer();
+            }
+            Command::Pasv => self.pasv()?,
             Command::Cwd(directory) => self.cwd(directory),
             Command::Cdup => {
                 // Using canonical parent path for better compatibility
@@ -155,134 +277,1047 @@ impl Client {
                 self.cwd(parent);
             }
             Command::Mkdir(directory) => {
-                let server_root = env::current_dir().unwrap();
-                let path = self.cwd.join(&directory);
-                if let Ok(dir) = self.complete_path(path, &server_root) {
-                    if let Err(_) = create_dir(&dir) {
-                        send_cmd(&mut self.stream, ResultCode::FileNameNotAllowed, "Couldn't create directory");
-                    } else {
-                        send_cmd(&mut self.stream, ResultCode::PATHNAMECreated, "Directory created");
-                    }
-                } else {
-                    send_cmd(&mut self.stream, ResultCode::FileNameNotAllowed, "Permission denied");
-                }
+                let path = self.path(&directory)?;
+                self.storage.mkdir(&path)?;
+                send_cmd(&mut self.stream, ResultCode::PATHNAMECreated, "Directory created");
+                self.fire(Event::new(EventKind::Mkd, self.user(), path));
             }
-            Command::Rmd(directory) => {
-                let server_root = env::current_dir().unwrap();
-                let path = self.cwd.join(&directory);
-                if let Ok(dir) = self.complete_path(path, &server_root) {
-                    if let Err(_) = remove_dir_all(&dir) {
-                        send_cmd(&mut self.stream, ResultCode::FileNameNotAllowed, "Couldn't remove directory");
-                    } else {
-                        send_cmd(&mut self.stream, ResultCode::RequestedFileActionOkay, "Directory removed");
-                    }
-                } else {
-                    send_cmd(&mut self.stream, ResultCode::FileNameNotAllowed, "Permission denied");
+            Command::Rmd(directory) => self.delete(directory, true),
+            Command::Dele(path) => self.delete(path, false),
+            Command::Rnfr(path) => self.rnfr(path),
+            Command::Rnto(path) => self.rnto(path),
+            Command::Site(args) => self.site(args),
+            Command::Rest(offset) => match offset.trim().parse() {
+                Ok(offset) => {
+                    self.restart = Some(offset);
+                    send_cmd(&mut self.stream, ResultCode::RequestFurtherInformation, &format!("Restarting at {}. Send STOR or RETR.", offset));
                 }
-            }
+                Err(_) => send_cmd(&mut self.stream, ResultCode::InvalidParameterOrArgument, "Invalid offset."),
+            },
+            // ALLO <size> [R <record size>]: nothing to reserve, but the size tells a
+            // segmented upload from a short one.
+            Command::Allo(size) => match size.split_whitespace().next().unwrap_or_default().parse() {
+                Ok(size) => {
+                    self.allocated = Some(size);
+                    send_cmd(&mut self.stream, ResultCode::Ok, &format!("Allocated {} bytes.", size));
+                }
+                Err(_) => send_cmd(&mut self.stream, ResultCode::InvalidParameterOrArgument, "Invalid size."),
+            },
+            Command::Size(path) => self.size(path)?,
+            Command::Hash(path) => self.hash(path)?,
+            Command::Avbl(path) => self.avbl(path)?,
             Command::Unknown(command) => {
                 send_cmd(&mut self.stream, ResultCode::UnknownCommand, &format!("Unknown command: {}", command));
             }
         }
+        Ok(())
     }
 
-    fn stor(&mut self, path: PathBuf) {
-        let server_root = env::current_dir().unwrap();
-        let path = self.cwd.join(path);
+    fn log_in(&mut self, username: String) {
+        self.session.update(|info| info.user = Some(username.clone()));
+        send_cmd(&mut self.stream, ResultCode::UserLoggedIn, &format!("Welcome {}", username));
+        self.name = Some(username);
+    }
 
-        if let Ok(file_path) = self.complete_path(path, &server_root) {
-            send_cmd(&mut self.stream, ResultCode::OpeningDataConnection, "Opening binary mode data connection for file upload.");
-            if let Some(ref mut writer) = self.data_writer {
-                let mut file = OpenOptions::new().write(true).create(true).open(file_path).unwrap();
-                let mut buffer = [0u8; 1024];
-                loop {
-                    match writer.read(&mut buffer) {
-                        Ok(0) => break,
-                        Ok(n) => {
-                            file.write_all(&buffer[..n]).unwrap();
-                        }
-                        Err(_) => {
-                            send_cmd(&mut self.stream, ResultCode::FileActionNotTaken, "Failed to receive file.");
-                            return;
-                        }
-                    }
-                }
-                send_cmd(&mut self.stream, ResultCode::ClosingDataConnection, "File transfer complete.");
-            } else {
-                send_cmd(&mut self.stream, ResultCode::CantOpenDataConnection, "No data connection.");
+    /// Logs in as the anonymous guest, inside the guest share, if there's room for one more.
+    fn guest_log_in(&mut self) {
+        let share = PathBuf::from("/").join(&self.config.anonymous.share);
+        let exists = self.config.shares.iter().any(|config| config.name == self.config.anonymous.share);
+        if !exists || self.storage.stat(&share).is_err() {
+            println!("[!] Anonymous share \"{}\" isn't configured", self.config.anonymous.share);
+            send_cmd(&mut self.stream, ResultCode::NotLoggedIn, "Anonymous access is unavailable");
+            return;
+        }
+        let Some(pass) = self.state.sessions.admit_guest(self.config.anonymous.max_connections) else {
+            send_cmd(&mut self.stream, ResultCode::NotLoggedIn, "Too many anonymous users, try again later");
+            return;
+        };
+        self.guest = Some(pass);
+        self.session.update(|info| info.cwd = share.clone());
+        self.cwd = share;
+        self.log_in("anonymous".to_string());
+    }
+
+    /// Checks the password of the user given to USER. Failures are answered after a delay
+    /// growing with each failure, and ban the client's address after too many.
+    fn pass(&mut self, password: String) {
+        let Some(username) = self.pending_user.take() else {
+            send_cmd(&mut self.stream, ResultCode::BadSequenceOfCommands, "Login with USER first");
+            return;
+        };
+        if self.config.anonymous.enabled && is_guest_name(&username) {
+            self.guest_log_in();
+            return;
+        }
+        if let Some(left) = self.state.guard.user_banned(&username) {
+            send_cmd(&mut self.stream, ResultCode::NotLoggedIn, &format!("Too many failed logins, try again in {}s", left.as_secs() + 1));
+            return;
+        }
+
+        // Compare digests, so the time taken doesn't depend on how much of the password matched.
+        let digest = Sha256::digest(password.as_bytes());
+        let valid = self
+            .config
+            .users
+            .iter()
+            .any(|user| user.name == username && Sha256::digest(user.password.as_bytes()) == digest);
+        if valid {
+            self.state.guard.success(&username);
+            self.log_in(username);
+            return;
+        }
+
+        // Failures are counted per IP, so without one the session can't go on.
+        let Ok(peer) = self.stream.peer_addr() else {
+            send_cmd(&mut self.stream, ResultCode::ServiceNotAvailable, "Couldn't identify your address, closing connection");
+            let _ = self.stream.shutdown(Shutdown::Both);
+            return;
+        };
+
+        println!("[!] Failed login for {} from {}", username, peer.ip());
+        let (delay, banned) = self.state.guard.failure(peer.ip(), &username, &self.config.security);
+        thread::sleep(delay);
+        if banned {
+            send_cmd(&mut self.stream, ResultCode::ServiceNotAvailable, "Too many failed logins, closing connection");
+            let _ = self.stream.shutdown(Shutdown::Both);
+        } else {
+            send_cmd(&mut self.stream, ResultCode::NotLoggedIn, "Login incorrect");
+        }
+    }
+
+    fn mode(&mut self, mode: String) {
+        match mode.trim().to_ascii_uppercase().as_str() {
+            "S" => {
+                self.mode = TransferMode::Stream;
+                send_cmd(&mut self.stream, ResultCode::Ok, "Mode set to S.");
+            }
+            "Z" if self.config.compression.enabled => {
+                self.mode = TransferMode::Deflate(self.config.compression.level.min(9));
+                send_cmd(&mut self.stream, ResultCode::Ok, "Mode set to Z.");
             }
+            _ => send_cmd(&mut self.stream, ResultCode::CommandNotImplementedForThatParameter, "Unsupported mode."),
+        }
+    }
+
+    fn user(&self) -> &str {
+        self.name.as_deref().unwrap_or("anonymous")
+    }
+
+    fn fire(&self, event: Event) {
+        if let Some(from) = &event.from {
+            self.state.changes.record(from);
+        }
+        self.state.changes.record(&event.path);
+        hooks::fire(&self.config.hooks, &self.storage, event);
+    }
+
+    fn trash(&self) -> Trash {
+        Trash::new(Arc::clone(&self.storage), self.user(), &self.config.trash)
+    }
+
+    /// RMD and DELE: moves the target into the user's trash, or deletes it right away
+    /// when the trash is disabled.
+    fn delete(&mut self, path: PathBuf, directory: bool) {
+        let target = match self.path(&path) {
+            Ok(target) => target,
+            Err(e) => {
+                send_cmd(&mut self.stream, ResultCode::FileNameNotAllowed, &e.to_string());
+                return;
+            }
+        };
+
+        let size = match self.storage.stat(&target) {
+            Ok(metadata) if metadata.is_dir == directory => metadata.len,
+            _ => {
+                let msg = if directory { "No such directory" } else { "No such file" };
+                send_cmd(&mut self.stream, ResultCode::FileUnavailable, msg);
+                return;
+            }
+        };
+
+        let result = if self.config.trash.enabled {
+            self.trash()
+                .put(&target)
+                .map(|id| println!("[*] Moved {} to trash as {}", target.display(), id))
         } else {
-            send_cmd(&mut self.stream, ResultCode::FileUnavailable, "Invalid path.");
+            self.storage.delete(&target)
+        };
+
+        match (result, directory) {
+            (Ok(()), true) => {
+                send_cmd(&mut self.stream, ResultCode::RequestedFileActionOkay, "Directory removed");
+                self.fire(Event::new(EventKind::Rmd, self.user(), target));
+            }
+            (Ok(()), false) => {
+                send_cmd(&mut self.stream, ResultCode::RequestedFileActionOkay, "File deleted");
+                self.fire(Event { size: Some(size), ..Event::new(EventKind::Dele, self.user(), target) });
+            }
+            (Err(_), true) => send_cmd(&mut self.stream, ResultCode::FileNameNotAllowed, "Couldn't remove directory"),
+            (Err(_), false) => send_cmd(&mut self.stream, ResultCode::FileNameNotAllowed, "Couldn't delete file"),
         }
-        self.data_writer = None;
     }
 
-    fn retr(&mut self, path: PathBuf) {
-        let server_root = env::current_dir().unwrap();
-        let path = self.cwd.join(path);
-
-        if let Ok(file_path) = self.complete_path(path, &server_root) {
-            if let Ok(mut file) = File::open(file_path) {
-                send_cmd(&mut self.stream, ResultCode::OpeningDataConnection, "Opening binary mode data connection for file download.");
-                if let Some(ref mut writer) = self.data_writer {
-                    let mut buffer = [0u8; 1024];
-                    loop {
-                        match file.read(&mut buffer) {
-                            Ok(0) => break,
-                            Ok(n) => {
-                                writer.write_all(&buffer[..n]).unwrap();
-                            }
-                            Err(_) => {
-                                send_cmd(&mut self.stream, ResultCode::FileActionNotTaken, "Failed to send file.");
-                                return;
-                            }
-                        }
-                    }
-                    send_cmd(&mut self.stream, ResultCode::ClosingDataConnection, "File transfer complete.");
-                } else {
-                    send_cmd(&mut self.stream, ResultCode::CantOpenDataConnection, "No data connection.");
+    fn rnfr(&mut self, path: PathBuf) {
+        match self.path(&path) {
+            Ok(from) if self.storage.stat(&from).is_ok() => {
+                self.rename_from = Some(from);
+                send_cmd(&mut self.stream, ResultCode::RequestFurtherInformation, "Ready for RNTO");
+            }
+            Ok(_) => send_cmd(&mut self.stream, ResultCode::FileUnavailable, "No such file or directory"),
+            Err(e) => send_cmd(&mut self.stream, ResultCode::FileUnavailable, &e.to_string()),
+        }
+    }
+
+    /// Renames the RNFR source. Replacing a file keeps its previous content as a version.
+    fn rnto(&mut self, path: PathBuf) {
+        let Some(from) = self.rename_from.take() else {
+            send_cmd(&mut self.stream, ResultCode::BadSequenceOfCommands, "RNFR required first");
+            return;
+        };
+        let to = match self.path(&path) {
+            Ok(to) => to,
+            Err(e) => {
+                send_cmd(&mut self.stream, ResultCode::FileNameNotAllowed, &e.to_string());
+                return;
+            }
+        };
+
+        let size = self.storage.stat(&from).map(|metadata| metadata.len).ok();
+        match self.storage.stat(&to) {
+            Ok(metadata) if metadata.is_dir => {
+                send_cmd(&mut self.stream, ResultCode::FileNameNotAllowed, "Target is a directory");
+                return;
+            }
+            Ok(_) if self.config.versions.enabled && from != to => {
+                if let Err(e) = self.versions().save(&to) {
+                    println!("[!] Couldn't keep previous version of {}: {}", to.display(), e);
                 }
-            } else {
-                send_cmd(&mut self.stream, ResultCode::FileUnavailable, "File not found.");
             }
-        } else {
-            send_cmd(&mut self.stream, ResultCode::FileUnavailable, "Invalid path.");
+            _ => {}
+        }
+
+        match self.storage.rename(&from, &to) {
+            Ok(()) => {
+                send_cmd(&mut self.stream, ResultCode::RequestedFileActionOkay, "Rename successful");
+                self.fire(Event { from: Some(from), size, ..Event::new(EventKind::Rnto, self.user(), to) });
+            }
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => send_cmd(&mut self.stream, ResultCode::FileNameNotAllowed, &e.to_string()),
+            Err(_) => send_cmd(&mut self.stream, ResultCode::FileNameNotAllowed, "Couldn't rename"),
+        }
+    }
+
+    fn site(&mut self, args: String) {
+        let args = args.trim();
+        let (cmd, rest) = args.split_once(' ').unwrap_or((args, ""));
+        match cmd.to_ascii_uppercase().as_str() {
+            "TRASH" => self.site_trash(rest.split_whitespace().collect()),
+            "VERSIONS" => self.site_versions(rest.trim()),
+            "DEDUP" => self.site_dedup(rest.trim()),
+            "WAIT" => self.site_wait(rest.split_whitespace().collect()),
+            "FIND" => self.site_find(rest),
+            "DU" => self.site_du(rest.trim()),
+            _ => send_cmd(&mut self.stream, ResultCode::CommandNotImplementedForThatParameter, "Unknown SITE command"),
+        }
+    }
+
+    /// SITE WAIT <cursor> <seconds>: blocks until something the user can see changes
+    /// after `cursor`, or for at most `seconds`. The last line of the reply is the next
+    /// cursor, then `CHANGED` after the changed paths, `TIMEOUT`, or `RESYNC` when the
+    /// cursor is unknown (e.g. `-`) or too old and the client must sync in full.
+    fn site_wait(&mut self, args: Vec<&str>) {
+        let timeout = match args[..] {
+            [_, seconds] => seconds.parse::<u64>().ok(),
+            _ => None,
+        };
+        let Some(timeout) = timeout else {
+            send_cmd(&mut self.stream, ResultCode::InvalidParameterOrArgument, "Usage: SITE WAIT <cursor> <seconds>");
+            return;
+        };
+        let timeout = Duration::from_secs(timeout).min(changes::MAX_WAIT);
+
+        let state = Arc::clone(&self.state);
+        let (cursor, wait) = state.changes.wait(args[0], timeout, |path| self.may_access(path));
+        match wait {
+            Wait::Changed(paths) => {
+                let mut reply = Reply::new(ResultCode::Ok, "Changed:");
+                for path in &paths {
+                    reply.push(path.display().to_string());
+                }
+                reply.push(format!("{} CHANGED", cursor));
+                send_reply(&mut self.stream, &reply);
+            }
+            Wait::TimedOut => send_cmd(&mut self.stream, ResultCode::Ok, &format!("{} TIMEOUT", cursor)),
+            Wait::Resync => send_cmd(&mut self.stream, ResultCode::Ok, &format!("{} RESYNC", cursor)),
+        }
+    }
+
+    /// SITE FIND [-a] [-larger <bytes>] [-smaller <bytes>] [-newer <time>] [-older <time>] <pattern>:
+    /// searches the tree under the working directory by glob or substring, and replies
+    /// with a line of MLSD facts per match, at most `MAX_FOUND`.
+    fn site_find(&mut self, args: &str) {
+        let query = match FindQuery::parse(args) {
+            Ok(query) => query,
+            Err(e) => {
+                send_cmd(&mut self.stream, ResultCode::InvalidParameterOrArgument, &format!("{}. Usage: SITE FIND [-a] [-larger <bytes>] [-smaller <bytes>] [-newer <time>] [-older <time>] <pattern>", e));
+                return;
+            }
+        };
+
+        let root = self.cwd.clone();
+        let mut found = Vec::new();
+        let mut truncated = false;
+        let result = storage::walk(&*self.storage, &root, &mut |relative, entry| {
+            let path = root.join(relative);
+            let hidden = relative
+                .components()
+                .any(|component| component.as_os_str().to_string_lossy().starts_with('.'));
+            if (hidden && !query.all) || !self.may_access(&path) || !query.matches(&entry.name, &entry.metadata) {
+                return Ok(());
+            }
+            if found.len() == MAX_FOUND {
+                truncated = true;
+                // Stops the walk; the matches so far are still sent.
+                return Err(ErrorKind::Interrupted.into());
+            }
+            found.push(find::facts(&path, &entry.metadata));
+            Ok(())
+        });
+        if let (Err(e), false) = (result, truncated) {
+            println!("[!] SITE FIND failed: {}", e);
+            send_cmd(&mut self.stream, ResultCode::LocalErrorInProcessing, "Search failed");
+            return;
+        }
+
+        let mut reply = Reply::new(ResultCode::Ok, "Matches:");
+        let count = found.len();
+        for line in found {
+            reply.push(line);
+        }
+        reply.push(match truncated {
+            true => format!("{} match(es), more not shown", count),
+            false => format!("{} match(es)", count),
+        });
+        send_reply(&mut self.stream, &reply);
+    }
+
+    /// SITE DU [<path>]: total size, file and directory count of the tree under a
+    /// directory, the working directory by default, as `size=...;files=...;dirs=...; path`.
+    fn site_du(&mut self, path: &str) {
+        let path = match self.path(Path::new(if path.is_empty() { "." } else { path })) {
+            Ok(path) => path,
+            Err(e) => {
+                send_cmd(&mut self.stream, ResultCode::FileUnavailable, &e.to_string());
+                return;
+            }
+        };
+        let message = match self.storage.stat(&path) {
+            Ok(metadata) if metadata.is_dir => None,
+            Ok(_) => Some("Not a directory."),
+            Err(_) => Some("No such file or directory"),
+        };
+        if let Some(message) = message {
+            send_cmd(&mut self.stream, ResultCode::FileUnavailable, message);
+            return;
+        }
+
+        let (mut size, mut files, mut dirs) = (0u64, 0u64, 0u64);
+        let result = storage::walk(&*self.storage, &path, &mut |relative, entry| {
+            if !self.may_access(&path.join(relative)) {
+                return Ok(());
+            }
+            match entry.metadata.is_dir {
+                true => dirs += 1,
+                false => {
+                    files += 1;
+                    size += entry.metadata.len;
+                }
+            }
+            Ok(())
+        });
+        match result {
+            Ok(()) => send_cmd(
+                &mut self.stream,
+                ResultCode::Ok,
+                &format!("size={};files={};dirs={}; {}", size, files, dirs, path.display()),
+            ),
+            Err(e) => {
+                println!("[!] SITE DU failed: {}", e);
+                send_cmd(&mut self.stream, ResultCode::LocalErrorInProcessing, "Couldn't measure directory");
+            }
         }
-        self.data_writer = None;
     }
 
-    fn list(&mut self) {
-        let server_root = env::current_dir().unwrap();
-        let path = self.cwd.join(".");
-        if let Ok(dir) = self.complete_path(path, &server_root) {
-            match read_dir(&dir) {
+    fn site_trash(&mut self, args: Vec<&str>) {
+        let trash = self.trash();
+        let sub = args.first().map(|cmd| cmd.to_ascii_uppercase());
+        match (sub.as_deref(), args.get(1)) {
+            (Some("LIST"), None) => match trash.list() {
                 Ok(entries) => {
-                    let mut response = String::new();
-                    for entry in entries {
-                        if let Ok(entry) = entry {
-                            let metadata = entry.metadata().unwrap();
-                            let file_type = if metadata.is_dir() { "DIR" } else { "FILE" };
-                            response.push_str(&format!(
-                                "{}\t{}\t{}\r\n",
-                                file_type,
-                                metadata.len(),
-                                entry.file_name().to_string_lossy()
-                            ));
-                        }
+                    let mut reply = Reply::new(ResultCode::Ok, "Trash contents (id, deleted at, path):");
+                    for entry in &entries {
+                        reply.push(format!("{}\t{}\t{}", entry.id, entry.deleted, entry.path.display()));
                     }
-                    send_cmd(&mut self.stream, ResultCode::FileStatusOk, "Here comes the directory listing.");
-                    if let Some(ref mut writer) = self.data_writer {
-                        write!(writer, "{}\r\n", response).unwrap();
-                        writer.flush().unwrap(); // Flush the data socket
+                    reply.push(format!("{} item(s)", entries.len()));
+                    send_reply(&mut self.stream, &reply);
+                }
+                Err(_) => send_cmd(&mut self.stream, ResultCode::LocalErrorInProcessing, "Couldn't read trash"),
+            },
+            (Some("RESTORE"), Some(id)) => match trash.restore(id) {
+                Ok(path) => {
+                    self.state.changes.record(&path);
+                    send_cmd(&mut self.stream, ResultCode::RequestedFileActionOkay, &format!("Restored \"{}\"", path.display()))
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => send_cmd(&mut self.stream, ResultCode::FileUnavailable, "Original path is taken"),
+                Err(e) if e.kind() == ErrorKind::PermissionDenied => send_cmd(&mut self.stream, ResultCode::FileNameNotAllowed, &e.to_string()),
+                Err(e) if e.kind() == ErrorKind::NotFound || e.kind() == ErrorKind::InvalidInput => {
+                    send_cmd(&mut self.stream, ResultCode::FileUnavailable, "No such trash item")
+                }
+                Err(_) => send_cmd(&mut self.stream, ResultCode::LocalErrorInProcessing, "Couldn't restore item"),
+            },
+            (Some("PURGE"), id) => match trash.purge(id.copied()) {
+                Ok(count) => send_cmd(&mut self.stream, ResultCode::RequestedFileActionOkay, &format!("Purged {} item(s)", count)),
+                Err(_) => send_cmd(&mut self.stream, ResultCode::FileUnavailable, "No such trash item"),
+            },
+            _ => send_cmd(&mut self.stream, ResultCode::InvalidParameterOrArgument, "Usage: SITE TRASH LIST | RESTORE <id> | PURGE [<id>]"),
+        }
+    }
+
+    fn versions(&self) -> Versions {
+        Versions::new(Arc::clone(&self.storage), &self.config.versions)
+    }
+
+    /// SITE VERSIONS LIST <path> | RESTORE <id> <path>
+    fn site_versions(&mut self, args: &str) {
+        let (sub, rest) = args.split_once(' ').unwrap_or((args, ""));
+        let (id, path) = match sub.to_ascii_uppercase().as_str() {
+            "LIST" if !rest.is_empty() => (None, rest),
+            "RESTORE" => match rest.split_once(' ') {
+                Some((id, path)) if !path.is_empty() => (Some(id), path),
+                _ => (None, ""),
+            },
+            _ => (None, ""),
+        };
+        if path.is_empty() {
+            send_cmd(&mut self.stream, ResultCode::InvalidParameterOrArgument, "Usage: SITE VERSIONS LIST <path> | RESTORE <id> <path>");
+            return;
+        }
+
+        let path = match self.path(Path::new(path)) {
+            Ok(path) => path,
+            Err(e) => {
+                send_cmd(&mut self.stream, ResultCode::FileNameNotAllowed, &e.to_string());
+                return;
+            }
+        };
+
+        let versions = self.versions();
+        match id {
+            None => match versions.list(&path) {
+                Ok(list) => {
+                    let mut reply = Reply::new(ResultCode::Ok, &format!("Versions of \"{}\" (id, size, replaced at):", path.display()));
+                    for version in &list {
+                        reply.push(format!("{}\t{}\t{}", version.id, version.size, version.created));
                     }
-                    send_cmd(&mut self.stream, ResultCode::ClosingDataConnection, "Directory send OK.");
+                    reply.push(format!("{} version(s)", list.len()));
+                    send_reply(&mut self.stream, &reply);
+                }
+                Err(_) => send_cmd(&mut self.stream, ResultCode::LocalErrorInProcessing, "Couldn't read versions"),
+            },
+            Some(id) => match versions.restore(&path, id) {
+                Ok(()) => {
+                    self.state.changes.record(&path);
+                    send_cmd(&mut self.stream, ResultCode::RequestedFileActionOkay, &format!("Restored version {} of \"{}\"", id, path.display()))
+                }
+                Err(_) => send_cmd(&mut self.stream, ResultCode::FileUnavailable, "No such version"),
+            },
+        }
+    }
+
+    /// SITE DEDUP [GC]
+    fn site_dedup(&mut self, args: &str) {
+        let Some(dedup) = self.storage.dedup() else {
+            send_cmd(&mut self.stream, ResultCode::CommandNotImplementedForThatParameter, "Deduplication is disabled");
+            return;
+        };
+        match args.to_ascii_uppercase().as_str() {
+            "" => match dedup.stats() {
+                Ok(stats) => {
+                    let saved = stats.logical.saturating_sub(stats.stored);
+                    let percent = (saved * 100).checked_div(stats.logical).unwrap_or(0);
+                    let mut reply = Reply::new(ResultCode::Ok, "Deduplication statistics:");
+                    reply.push(format!("Files: {} ({} bytes)", stats.files, stats.logical));
+                    reply.push(format!("Stored chunks: {} ({} bytes)", stats.chunks, stats.stored));
+                    reply.push(format!("Saved: {} bytes ({}%)", saved, percent));
+                    reply.push(format!("Unreferenced chunks: {} ({} bytes)", stats.orphans, stats.orphan_bytes));
+                    send_reply(&mut self.stream, &reply);
+                }
+                Err(_) => send_cmd(&mut self.stream, ResultCode::LocalErrorInProcessing, "Couldn't compute statistics"),
+            },
+            "GC" => match dedup.collect_garbage() {
+                Ok((count, bytes)) => send_cmd(&mut self.stream, ResultCode::Ok, &format!("Removed {} chunk(s), {} bytes freed", count, bytes)),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => send_cmd(&mut self.stream, ResultCode::FileActionNotTaken, &e.to_string()),
+                Err(_) => send_cmd(&mut self.stream, ResultCode::LocalErrorInProcessing, "Couldn't collect garbage"),
+            },
+            _ => send_cmd(&mut self.stream, ResultCode::InvalidParameterOrArgument, "Usage: SITE DEDUP [GC]"),
+        }
+    }
+
+    fn size(&mut self, path: PathBuf) -> error::Result<()> {
+        let metadata = self.storage.stat(&self.path(&path)?)?;
+        if metadata.is_dir {
+            return Err(FtpError::reply(ResultCode::FileUnavailable, "Not a regular file."));
+        }
+        send_cmd(&mut self.stream, ResultCode::FileStatus, &metadata.len.to_string());
+        Ok(())
+    }
+
+    /// AVBL, as in draft-peterson-streamlined-ftp-command-extensions: bytes that can
+    /// still be stored in a directory, the working directory by default.
+    fn avbl(&mut self, path: Option<PathBuf>) -> error::Result<()> {
+        let target = self.path(&path.unwrap_or_else(|| PathBuf::from(".")))?;
+        if !self.storage.stat(&target)?.is_dir {
+            return Err(FtpError::reply(ResultCode::FileUnavailable, "Not a directory."));
+        }
+        // Guests can't store anything.
+        let available = match self.guest {
+            Some(_) => 0,
+            None => self.storage.available(&target)?,
+        };
+        send_cmd(&mut self.stream, ResultCode::FileStatus, &available.to_string());
+        Ok(())
+    }
+
+    /// HASH, as in draft-bryan-ftpext-hash: the SHA-256 of a whole file, so clients can
+    /// check a file they put together from segments.
+    fn hash(&mut self, path: PathBuf) -> error::Result<()> {
+        let target = self.path(&path)?;
+        let metadata = self.storage.stat(&target)?;
+        if metadata.is_dir {
+            return Err(FtpError::reply(ResultCode::FileUnavailable, "Not a regular file."));
+        }
+        let hash = storage::sha256(self.storage.as_ref(), &target)?;
+        let msg = format!("SHA-256 0-{} {} {}", metadata.len.saturating_sub(1), hash, path.display());
+        send_cmd(&mut self.stream, ResultCode::FileStatus, &msg);
+        Ok(())
+    }
+
+    /// The IPv4 address PASV advertises, as configured in `[passive]`.
+    fn passive_address(&self) -> error::Result<Ipv4Addr> {
+        let address = self.config.passive.address.trim();
+        if !address.eq_ignore_ascii_case("auto") {
+            let resolved = (address, 0).to_socket_addrs().map(|mut addrs| {
+                addrs.find_map(|addr| match addr.ip() {
+                    IpAddr::V4(ip) => Some(ip),
+                    IpAddr::V6(_) => None,
+                })
+            });
+            match resolved {
+                Ok(Some(ip)) => return Ok(ip),
+                Ok(None) => println!("[!] Passive address {} has no IPv4 address, using the local one", address),
+                Err(e) => println!("[!] Couldn't resolve passive address {}: {}, using the local one", address, e),
+            }
+        }
+
+        // The address the client connected to. PASV can't carry IPv6, so a client on
+        // IPv6 is sent localhost, unless it came through an IPv4-mapped address.
+        Ok(match self.stream.local_addr()?.ip() {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(ip) => ip.to_ipv4_mapped().unwrap_or(Ipv4Addr::LOCALHOST),
+        })
+    }
+
+    fn pasv(&mut self) -> error::Result<()> {
+        if self.data_writer.is_some() {
+            send_cmd(&mut self.stream, ResultCode::FileStatusOk, "Already listening...");
+            return Ok(());
+        }
+
+        // A port of its own for each session, so several can transfer at once. It's
+        // bound before replying, so the client can connect as soon as it knows it.
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0); // Bind to all interfaces
+        let listener = TcpListener::bind(addr).map_err(|e| {
+            println!("Error binding to data port: {}", e);
+            FtpError::reply(ResultCode::CantOpenDataConnection, "Failed to open data connection.")
+        })?;
+        let port = listener.local_addr()?.port();
+
+        let ip_parts = self.passive_address()?.octets();
+        let p1 = port / 256;
+        let p2 = port % 256;
+
+        send_cmd(
+            &mut self.stream,
+            ResultCode::EnteringPassiveMode,
+            &format!("Entering Passive Mode ({},{},{},{},{},{})",
+                     ip_parts[0], ip_parts[1], ip_parts[2], ip_parts[3], p1, p2),
+        );
+
+        match listener.incoming().next() {
+            Some(Ok(client)) => {
+                self.session.set_data(Some(&client));
+                self.data_writer = Some(client);
+                Ok(())
+            }
+            Some(Err(e)) => {
+                println!("Error accepting data connection: {}", e);
+                Err(FtpError::reply(ResultCode::CantOpenDataConnection, "Failed to open data connection."))
+            }
+            None => {
+                println!("No incoming data connection.");
+                Err(FtpError::reply(ResultCode::CantOpenDataConnection, "Failed to open data connection."))
+            }
+        }
+    }
+
+    /// Closes the data connection after a transfer or listing, however it ended.
+    fn end_transfer(&mut self) {
+        self.data_writer = None;
+        self.restart = None;
+        self.allocated = None;
+        self.session.set_data(None);
+        self.session.update(|info| info.transfer = None);
+    }
+
+    fn stor(&mut self, path: PathBuf) -> error::Result<()> {
+        let file_path = self.path(&path)?;
+        let offset = self.restart.take();
+        let allocated = self.allocated.take();
+        if self.data_writer.is_none() {
+            return Err(FtpError::NoDataConnection);
+        }
+        // After REST, the upload is a segment written into the existing file. Otherwise
+        // it goes to a temporary file that replaces the target once complete, so a failed
+        // upload leaves the previous content where it was.
+        let (file, temp) = match offset {
+            Some(offset) => (self.storage.write_at(&file_path, offset)?, None),
+            None => {
+                if self.storage.stat(&file_path).is_ok_and(|metadata| metadata.is_dir) {
+                    return Err(FtpError::reply(ResultCode::FileUnavailable, "Is a directory."));
+                }
+                let temp = storage::staged_path(&file_path, &format!("upload-{}", self.session.id));
+                (self.storage.write(&temp)?, Some(temp))
+            }
+        };
+        send_cmd(&mut self.stream, ResultCode::OpeningDataConnection, "Opening binary mode data connection for file upload.");
+        self.session.start_transfer("STOR", file_path.clone(), None);
+
+        let result = self.receive(file).and_then(|received| match &temp {
+            Some(temp) => self.replace(temp, &file_path).map(|()| received),
+            None => Ok(received),
+        });
+        if let (Err(_), Some(temp)) = (&result, &temp) {
+            let _ = self.storage.delete(temp);
+        }
+        let received = result?;
+        send_cmd(&mut self.stream, ResultCode::ClosingDataConnection, "File transfer complete.");
+        // A segmented upload starts with a STOR short of its ALLO, and is announced once
+        // its segments are all in.
+        let complete = match (&temp, allocated) {
+            (None, _) => self.state.segments.add(&file_path, received),
+            (Some(_), Some(size)) if received < size => {
+                self.state.segments.expect(&file_path, size, received);
+                false
+            }
+            (Some(_), _) => {
+                self.state.segments.cancel(&file_path);
+                true
+            }
+        };
+        if complete {
+            let size = self.storage.stat(&file_path).map(|metadata| metadata.len).ok();
+            self.fire(Event { size, ..Event::new(EventKind::Stor, self.user(), file_path) });
+        }
+        Ok(())
+    }
+
+    /// Copies an upload from the data connection into `file`, and returns its size.
+    fn receive(&mut self, file: Box<dyn Write + Send>) -> error::Result<u64> {
+        let Some(ref mut writer) = self.data_writer else {
+            return Err(FtpError::NoDataConnection);
+        };
+
+        let watch = AbortWatch::start(&self.stream, writer).map_err(|e| println!("[!] Couldn't watch for ABOR: {}", e)).ok();
+        let aborted = || watch.as_ref().is_some_and(AbortWatch::aborted);
+        let mut file = BufWriter::with_capacity(BUFFER_SIZE, file);
+        let mut reader = DataReader::new(writer, self.mode);
+        let mut buffer = vec![0u8; BUFFER_SIZE];
+        let mut received = 0;
+        loop {
+            match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => {
+                    file.write_all(&buffer[..n])?;
+                    received += n as u64;
+                    self.session.progress(n);
+                }
+                Err(_) if aborted() => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        // An aborted upload ends like a complete one, as the data connection is shut down.
+        if aborted() {
+            return Err(FtpError::reply(ResultCode::ConnectionClosed, "Transfer aborted."));
+        }
+        file.flush()?;
+        Ok(received)
+    }
+
+    /// Puts a complete upload in place of `path`, keeping the previous content as a version.
+    fn replace(&self, temp: &Path, path: &Path) -> error::Result<()> {
+        if self.config.versions.enabled && self.storage.stat(path).is_ok_and(|metadata| !metadata.is_dir) {
+            if let Err(e) = self.versions().save(path) {
+                println!("[!] Couldn't keep previous version of {}: {}", path.display(), e);
+            }
+        }
+        self.storage.rename(temp, path)?;
+        Ok(())
+    }
+
+    fn retr(&mut self, path: PathBuf) -> error::Result<()> {
+        let offset = self.restart.take().unwrap_or(0);
+        let path = self.path(&path)?;
+        let mut file = self.storage.read(&path)?;
+        send_cmd(&mut self.stream, ResultCode::OpeningDataConnection, "Opening binary mode data connection for file download.");
+        let size = self.storage.stat(&path).map(|metadata| metadata.len).ok();
+        self.session.start_transfer("RETR", path.clone(), size);
+        let Some(ref mut writer) = self.data_writer else {
+            return Err(FtpError::NoDataConnection);
+        };
+
+        let watch = AbortWatch::start(&self.stream, writer).map_err(|e| println!("[!] Couldn't watch for ABOR: {}", e)).ok();
+        // Sending fails when the client aborts or goes away.
+        let closed = || {
+            let aborted = watch.as_ref().is_some_and(AbortWatch::aborted);
+            FtpError::reply(ResultCode::ConnectionClosed, if aborted { "Transfer aborted." } else { "Data connection closed." })
+        };
+        let mut throttle = match self.config.anonymous.rate_limit_kib {
+            kib if kib > 0 && self.guest.is_some() => Some(Throttle::new(kib * 1024)),
+            _ => None,
+        };
+        // sendfile can't be paced, so throttled downloads go through the copy loop.
+        let local = match self.mode {
+            TransferMode::Stream if throttle.is_none() => self.storage.local_file(&path),
+            _ => None,
+        };
+
+        if let Some(mut local) = local {
+            local.seek(SeekFrom::Start(offset))?;
+            let session = &self.session;
+            transfer::send_file(&local, writer, &mut |n| session.progress(n)).map_err(|_| closed())?;
+        } else {
+            std::io::copy(&mut (&mut file).take(offset), &mut std::io::sink())?;
+            let mut writer = DataWriter::new(writer, self.mode);
+            let mut buffer = vec![0u8; throttle.as_ref().map_or(BUFFER_SIZE, Throttle::chunk)];
+            loop {
+                let n = file.read(&mut buffer)?;
+                if n == 0 {
+                    break;
                 }
+                writer.write_all(&buffer[..n]).map_err(|_| closed())?;
+                self.session.progress(n);
+                if let Some(throttle) = &mut throttle {
+                    throttle.pace(n);
+                }
+            }
+            writer.finish().map_err(|_| closed())?;
+        }
+        send_cmd(&mut self.stream, ResultCode::ClosingDataConnection, "File transfer complete.");
+        Ok(())
+    }
+
+    /// LIST and NLST. The argument is optional flags followed by an optional path, e.g.
+    /// `-a docs`: `-a` includes dotfiles, `-R` lists the whole tree (see `list_tree`).
+    fn list(&mut self, arg: Option<PathBuf>, names_only: bool) {
+        let options = ListOptions::parse(arg);
+        let path = match self.path(&options.path) {
+            Ok(path) => path,
+            Err(e) => {
+                send_cmd(&mut self.stream, ResultCode::FileUnavailable, &e.to_string());
+                return;
+            }
+        };
+        let metadata = match self.storage.stat(&path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+                send_cmd(&mut self.stream, ResultCode::FileUnavailable, &e.to_string());
+                return;
+            }
+            Err(_) => {
+                send_cmd(&mut self.stream, ResultCode::FileUnavailable, "No such file or directory");
+                return;
+            }
+        };
+        if options.recursive && metadata.is_dir && !names_only {
+            self.list_tree(path, &options);
+            return;
+        }
+
+        let entries = if metadata.is_dir {
+            match self.storage.list(&path) {
+                Ok(entries) => entries
+                    .into_iter()
+                    .filter(|entry| listed(&path, &entry.name, options.all))
+                    .filter(|entry| self.may_access(&path.join(&entry.name)))
+                    .collect(),
                 Err(_) => {
-                    send_cmd(&mut self.stream, ResultCode::InvalidParameterOrArgument, "Failed to list directory.");
+                    send_cmd(&mut self.stream, ResultCode::LocalErrorInProcessing, "Failed to list directory.");
+                    return;
                 }
             }
         } else {
-            send_cmd(&mut self.stream, ResultCode::InvalidParameterOrArgument, "Permission denied");
+            // A file named explicitly is listed even if hidden.
+            let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            vec![Entry { name, metadata }]
+        };
+
+        let mut response = String::new();
+        for entry in entries {
+            if names_only {
+                response.push_str(&format!("{}\r\n", entry.name));
+            } else {
+                let file_type = if entry.metadata.is_dir { "DIR" } else { "FILE" };
+                response.push_str(&format!("{}\t{}\t{}\r\n", file_type, entry.metadata.len, entry.name));
+            }
+        }
+
+        let Some(ref mut data) = self.data_writer else {
+            send_cmd(&mut self.stream, ResultCode::CantOpenDataConnection, "No data connection.");
+            return;
+        };
+        send_cmd(&mut self.stream, ResultCode::FileStatusOk, "Here comes the directory listing.");
+        let mut writer = DataWriter::new(data, self.mode);
+        match write!(writer, "{}", response).and_then(|()| writer.finish()) {
+            Ok(()) => send_cmd(&mut self.stream, ResultCode::ClosingDataConnection, "Directory send OK."),
+            Err(_) => send_cmd(&mut self.stream, ResultCode::ConnectionClosed, "Failed to send directory listing."),
+        }
+    }
+
+    /// LIST -R: everything under `root` in a single listing, one
+    /// `TYPE\tsize\tmtime\thash\tpath` line per entry, with paths relative to `root`
+    /// and mtimes in Unix seconds. File hashes are SHA-256, computed only with -H;
+    /// otherwise, and for directories, the hash is `-`.
+    fn list_tree(&mut self, root: PathBuf, options: &ListOptions) {
+        let Some(ref mut data) = self.data_writer else {
+            send_cmd(&mut self.stream, ResultCode::CantOpenDataConnection, "No data connection.");
+            return;
+        };
+
+        send_cmd(&mut self.stream, ResultCode::FileStatusOk, "Here comes the directory tree.");
+        let storage = Arc::clone(&self.storage);
+        let config = Arc::clone(&self.config);
+        let user = self.name.as_deref().unwrap_or("anonymous");
+        // Guests are confined to their share, which holds all of the tree.
+        let guest = self.guest.is_some();
+        let mut writer = BufWriter::new(DataWriter::new(data, self.mode));
+        let result = storage::walk(&*storage, &root, &mut |relative, entry| {
+            let hidden = relative
+                .components()
+                .any(|component| component.as_os_str().to_string_lossy().starts_with('.'));
+            if (hidden && !options.all) || !(guest || config.may_access(user, &root.join(relative))) {
+                return Ok(());
+            }
+            let modified = entry
+                .metadata
+                .modified
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|modified| modified.as_secs().to_string())
+                .unwrap_or_else(|| "-".to_string());
+            let (kind, hash) = match entry.metadata.is_dir {
+                true => ("DIR", "-".to_string()),
+                false if options.hashes => ("FILE", storage::sha256(&*storage, &root.join(relative))?),
+                false => ("FILE", "-".to_string()),
+            };
+            write!(writer, "{}\t{}\t{}\t{}\t{}\r\n", kind, entry.metadata.len, modified, hash, relative.display())
+        })
+        .and_then(|()| writer.into_inner().map_err(|e| e.into_error())?.finish());
+
+        match result {
+            Ok(()) => send_cmd(&mut self.stream, ResultCode::ClosingDataConnection, "Directory send OK."),
+            Err(e) => {
+                println!("[!] Couldn't list {}: {}", root.display(), e);
+                send_cmd(&mut self.stream, ResultCode::LocalErrorInProcessing, "Failed to list directory.");
+            }
+        }
+    }
+}
+
+/// Flags and path given to LIST or NLST.
+struct ListOptions {
+    /// -a: include dotfiles.
+    all: bool,
+    /// -R: the whole tree.
+    recursive: bool,
+    /// -H: with -R, include file hashes.
+    hashes: bool,
+    path: PathBuf,
+}
+
+impl ListOptions {
+    /// Parses leading `-x` words as flags, and the rest as the path.
+    fn parse(arg: Option<PathBuf>) -> ListOptions {
+        let arg = arg.map(|arg| arg.to_string_lossy().to_string()).unwrap_or_default();
+        let mut rest = arg.trim();
+        let mut flags = String::new();
+        while let Some(word) = rest.strip_prefix('-') {
+            let (word, tail) = word.split_once(char::is_whitespace).unwrap_or((word, ""));
+            flags.push_str(word);
+            rest = tail.trim_start();
+        }
+
+        ListOptions {
+            all: flags.contains('a'),
+            recursive: flags.contains('R'),
+            hashes: flags.contains('H'),
+            path: PathBuf::from(if rest.is_empty() { "." } else { rest }),
+        }
+    }
+}
+
+/// SITE commands open to guests, as they change nothing.
+fn read_only_site(args: &str) -> bool {
+    let cmd = args.split_whitespace().next().unwrap_or_default();
+    ["WAIT", "FIND", "DU"].iter().any(|read_only| cmd.eq_ignore_ascii_case(read_only))
+}
+
+/// Names logging in as the anonymous guest, as is customary.
+fn is_guest_name(name: &str) -> bool {
+    name.eq_ignore_ascii_case("anonymous") || name.eq_ignore_ascii_case("ftp")
+}
+
+/// Whether an entry of `dir` shows up in listings: dotfiles only with -a, and the
+/// metadata directory and staged files never.
+fn listed(dir: &Path, name: &str, all: bool) -> bool {
+    let hidden = storage::is_staged(name) || (dir == Path::new("/") && name == META_DIR);
+    !hidden && (all || !name.starts_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+
+    use super::*;
+    use crate::config::StorageBackend;
+
+    /// A control connection to a server on memory storage, without users.
+    struct Session {
+        control: BufReader<TcpStream>,
+    }
+
+    impl Session {
+        fn start() -> Session {
+            let mut config = Config::default();
+            config.storage.backend = StorageBackend::Memory;
+            let state = Arc::new(ServerState::new(config));
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            thread::spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                Client::handle_client(stream, state);
+            });
+
+            let mut session = Session {
+                control: BufReader::new(TcpStream::connect(address).unwrap()),
+            };
+            assert!(session.reply().starts_with("220"));
+            session
+        }
+
+        /// The last line of the next reply.
+        fn reply(&mut self) -> String {
+            loop {
+                let mut line = String::new();
+                assert!(self.control.read_line(&mut line).unwrap() > 0, "connection closed");
+                let bytes = line.as_bytes();
+                if bytes.len() > 3 && bytes[..3].iter().all(u8::is_ascii_digit) && bytes[3] == b' ' {
+                    return line.trim_end().to_string();
+                }
+            }
+        }
+
+        fn cmd(&mut self, command: &str) -> String {
+            self.control.get_mut().write_all(format!("{}\r\n", command).as_bytes()).unwrap();
+            self.reply()
         }
+
+        fn pasv(&mut self) -> TcpStream {
+            let reply = self.cmd("PASV");
+            let numbers: Vec<u16> = reply[reply.find('(').unwrap() + 1..reply.find(')').unwrap()]
+                .split(',')
+                .map(|number| number.parse().unwrap())
+                .collect();
+            TcpStream::connect(("127.0.0.1", numbers[4] * 256 + numbers[5])).unwrap()
+        }
+
+        /// Uploads `data`, returning the reply that ends the transfer, or refuses it.
+        fn stor(&mut self, path: &str, data: &[u8]) -> String {
+            let mut data_stream = self.pasv();
+            let reply = self.cmd(&format!("STOR {}", path));
+            if !reply.starts_with("150") {
+                return reply;
+            }
+            data_stream.write_all(data).unwrap();
+            drop(data_stream);
+            self.reply()
+        }
+
+        fn retr(&mut self, path: &str) -> Vec<u8> {
+            let mut data_stream = self.pasv();
+            assert!(self.cmd(&format!("RETR {}", path)).starts_with("150"));
+            let mut data = Vec::new();
+            data_stream.read_to_end(&mut data).unwrap();
+            assert!(self.reply().starts_with("226"));
+            data
+        }
+    }
+
+    #[test]
+    fn rest_stor_writes_into_the_file() {
+        let mut session = Session::start();
+        assert!(session.stor("a.txt", b"0123456789").starts_with("226"));
+        assert!(session.cmd("REST 5").starts_with("350"));
+        assert!(session.stor("a.txt", b"XY").starts_with("226"));
+        assert_eq!(session.retr("a.txt"), b"01234XY789");
+
+        // From the end, it appends.
+        assert!(session.cmd("REST 10").starts_with("350"));
+        assert!(session.stor("a.txt", b"!").starts_with("226"));
+        assert_eq!(session.retr("a.txt"), b"01234XY789!");
+    }
+
+    #[test]
+    fn rest_stor_past_the_end_is_refused() {
+        let mut session = Session::start();
+        assert!(session.stor("a.txt", b"0123456789").starts_with("226"));
+        for offset in ["11", "18446744073709551615"] {
+            assert!(session.cmd(&format!("REST {}", offset)).starts_with("350"));
+            assert!(session.stor("a.txt", b"XY").starts_with("553"), "REST {}", offset);
+        }
+        assert!(session.cmd("REST -1").starts_with("501"));
+        assert!(session.cmd("REST 18446744073709551616").starts_with("501"));
+        assert_eq!(session.retr("a.txt"), b"0123456789");
+
+        // The refused offset doesn't carry over to the next upload.
+        assert!(session.stor("a.txt", b"new").starts_with("226"));
+        assert_eq!(session.retr("a.txt"), b"new");
+    }
+
+    #[test]
+    fn segmented_upload_is_announced_once_complete() {
+        let mut session = Session::start();
+        let reply = session.cmd("SITE WAIT - 0");
+        let cursor = reply[4..].split(' ').next().unwrap().to_string();
+
+        // The empty STOR short of its ALLO only sets up the file.
+        assert!(session.cmd("ALLO 10").starts_with("200"));
+        assert!(session.stor("a.txt", b"").starts_with("226"));
+        assert!(session.cmd(&format!("SITE WAIT {} 0", cursor)).ends_with("TIMEOUT"));
+        assert!(session.cmd("REST 0").starts_with("350"));
+        assert!(session.stor("a.txt", b"01234").starts_with("226"));
+        assert!(session.cmd(&format!("SITE WAIT {} 0", cursor)).ends_with("TIMEOUT"));
+
+        assert!(session.cmd("REST 5").starts_with("350"));
+        assert!(session.stor("a.txt", b"56789").starts_with("226"));
+        assert!(session.cmd(&format!("SITE WAIT {} 0", cursor)).ends_with("CHANGED"));
+        assert_eq!(session.retr("a.txt"), b"0123456789");
     }
 }
@@ -44,19 +44,42 @@ pub enum ResultCode {
     FileUnavailable = 550,
 }
 
+/// Commands listed by HELP.
+pub const SUPPORTED_COMMANDS: &[&str] = &[
+    "ABOR", "ALLO", "AUTH", "AVBL", "CDUP", "CWD", "DELE", "FEAT", "HASH", "HELP", "LIST", "MKD", "MODE",
+    "NLST", "PASS", "PASV", "PWD", "REST", "RETR", "RMD", "RNFR", "RNTO", "SITE", "SIZE", "STAT", "STOR",
+    "SYST", "TYPE", "USER",
+];
+
 #[derive(Clone, Debug)]
 pub enum Command {
+    Abor,
+    Allo(String),
     Auth,
     Syst,
     User(String),
+    Pass(String),
     Pwd,
     Type,
+    Mode(String),
+    Feat,
+    Help,
+    Stat(Option<PathBuf>),
     List(Option<PathBuf>),
+    Nlst(Option<PathBuf>),
     Pasv,
     Cwd(PathBuf),
     Cdup,
     Mkdir(PathBuf),
     Rmd(PathBuf),
+    Dele(PathBuf),
+    Rnfr(PathBuf),
+    Rnto(PathBuf),
+    Site(String),
+    Rest(String),
+    Size(PathBuf),
+    Hash(PathBuf),
+    Avbl(Option<PathBuf>),
     Stor(PathBuf),
     Retr(PathBuf),
     Unknown(String),
@@ -65,17 +88,33 @@ pub enum Command {
 impl AsRef<str> for Command {
     fn as_ref(&self) -> &str {
         match *self {
+            Command::Abor => "ABOR",
+            Command::Allo(_) => "ALLO",
             Command::Auth => "AUTH",
             Command::Syst => "SYST",
             Command::User(_) => "USER",
+            Command::Pass(_) => "PASS",
             Command::Pwd => "PWD",
             Command::Type => "TYPE",
+            Command::Mode(_) => "MODE",
+            Command::Feat => "FEAT",
+            Command::Help => "HELP",
+            Command::Stat(_) => "STAT",
             Command::List(_) => "LIST",
+            Command::Nlst(_) => "NLST",
             Command::Pasv => "PASV",
             Command::Cwd(_) => "CWD",
             Command::Cdup => "CDUP",
             Command::Mkdir(_) => "MKD",
             Command::Rmd(_) => "RMD",
+            Command::Dele(_) => "DELE",
+            Command::Rnfr(_) => "RNFR",
+            Command::Rnto(_) => "RNTO",
+            Command::Site(_) => "SITE",
+            Command::Rest(_) => "REST",
+            Command::Size(_) => "SIZE",
+            Command::Hash(_) => "HASH",
+            Command::Avbl(_) => "AVBL",
             Command::Unknown(_) => "UNKN",
             Command::Stor(_) => "STOR",
             Command::Retr(_) => "RETR",
@@ -84,29 +123,45 @@ impl AsRef<str> for Command {
 }
 
 impl Command {
-    pub fn new(input: Vec<u8>) -> std::io::Result<Self> {
-        let mut iter = input.split(|&byte| byte == b' ');
-        let command = iter.next().expect("command in input").to_vec();
+    pub fn new(input: Vec<u8>) -> Self {
+        let mut iter = input.splitn(2, |&byte| byte == b' ');
+        let command = iter.next().unwrap_or_default().to_vec();
         let command_lowercase: Vec<u8> = command.to_ascii_lowercase();
         let data = iter.next();
 
         let command = match command_lowercase.as_slice() {
+            b"abor" => Command::Abor,
+            b"allo" => Command::Allo(data.map(|bytes| String::from_utf8_lossy(bytes).to_string()).unwrap_or_default()),
             b"auth" => Command::Auth,
             b"syst" => Command::Syst,
             b"user" => Command::User(data.map(|bytes| String::from_utf8_lossy(bytes).to_string()).unwrap_or_default()),
+            b"pass" => Command::Pass(data.map(|bytes| String::from_utf8_lossy(bytes).to_string()).unwrap_or_default()),
             b"pwd" => Command::Pwd,
             b"type" => Command::Type,
+            b"mode" => Command::Mode(data.map(|bytes| String::from_utf8_lossy(bytes).to_string()).unwrap_or_default()),
+            b"feat" => Command::Feat,
+            b"help" => Command::Help,
+            b"stat" => Command::Stat(data.map(|bytes| PathBuf::from(String::from_utf8_lossy(bytes).to_string()))),
             b"list" => Command::List(data.map(|bytes| PathBuf::from(String::from_utf8_lossy(bytes).to_string()))),
+            b"nlst" => Command::Nlst(data.map(|bytes| PathBuf::from(String::from_utf8_lossy(bytes).to_string()))),
             b"pasv" => Command::Pasv,
             b"cwd" => Command::Cwd(data.map(|bytes| PathBuf::from(String::from_utf8_lossy(bytes).to_string())).unwrap_or_default()),
             b"cdup" => Command::Cdup,
             b"mkd" => Command::Mkdir(data.map(|bytes| PathBuf::from(String::from_utf8_lossy(bytes).to_string())).unwrap_or_default()),
             b"rmd" => Command::Rmd(data.map(|bytes| PathBuf::from(String::from_utf8_lossy(bytes).to_string())).unwrap_or_default()),
+            b"dele" => Command::Dele(data.map(|bytes| PathBuf::from(String::from_utf8_lossy(bytes).to_string())).unwrap_or_default()),
+            b"rnfr" => Command::Rnfr(data.map(|bytes| PathBuf::from(String::from_utf8_lossy(bytes).to_string())).unwrap_or_default()),
+            b"rnto" => Command::Rnto(data.map(|bytes| PathBuf::from(String::from_utf8_lossy(bytes).to_string())).unwrap_or_default()),
+            b"site" => Command::Site(data.map(|bytes| String::from_utf8_lossy(bytes).to_string()).unwrap_or_default()),
+            b"rest" => Command::Rest(data.map(|bytes| String::from_utf8_lossy(bytes).to_string()).unwrap_or_default()),
+            b"size" => Command::Size(data.map(|bytes| PathBuf::from(String::from_utf8_lossy(bytes).to_string())).unwrap_or_default()),
+            b"hash" => Command::Hash(data.map(|bytes| PathBuf::from(String::from_utf8_lossy(bytes).to_string())).unwrap_or_default()),
+            b"avbl" => Command::Avbl(data.map(|bytes| PathBuf::from(String::from_utf8_lossy(bytes).to_string()))),
             b"stor" => Command::Stor(data.map(|bytes| PathBuf::from(String::from_utf8_lossy(bytes).to_string())).unwrap_or_default()),
             b"retr" => Command::Retr(data.map(|bytes| PathBuf::from(String::from_utf8_lossy(bytes).to_string())).unwrap_or_default()),
             _ => Command::Unknown(String::from_utf8_lossy(&command).to_string()),
         };
 
-        Ok(command)
+        command
     }
 }
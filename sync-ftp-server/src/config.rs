@@ -0,0 +1,342 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::guard::Cidr;
+use crate::hooks::EventKind;
+use crate::jail::SymlinkPolicy;
+
+/// Directory inside the server root that holds server-private state (config, trash, ...).
+/// Clients can never see or reach it.
+pub const META_DIR: &str = ".ventus";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub trash: TrashConfig,
+    pub versions: VersionsConfig,
+    pub compression: CompressionConfig,
+    pub jail: JailConfig,
+    pub storage: StorageConfig,
+    pub hooks: Vec<HookConfig>,
+    pub admin: AdminConfig,
+    pub recording: RecordingConfig,
+    /// Accounts allowed to log in. When empty, USER alone logs in, under any name.
+    pub users: Vec<UserConfig>,
+    pub security: SecurityConfig,
+    /// Named shares. When set, the root only holds the shares instead of the files in the
+    /// server directory.
+    pub shares: Vec<ShareConfig>,
+    pub anonymous: AnonymousConfig,
+    pub passive: PassiveConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TrashConfig {
+    /// When disabled, RMD and DELE delete immediately.
+    pub enabled: bool,
+    /// Days a trashed item is kept before being purged. 0 keeps items forever.
+    pub retention_days: u64,
+}
+
+impl Default for TrashConfig {
+    fn default() -> Self {
+        TrashConfig {
+            enabled: true,
+            retention_days: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct VersionsConfig {
+    /// When enabled, STOR over an existing file keeps the previous content.
+    pub enabled: bool,
+    /// Number of newest versions always kept per file.
+    pub keep: usize,
+    /// Versions younger than this many days are kept even beyond `keep`. 0 disables it.
+    pub max_age_days: u64,
+}
+
+impl Default for VersionsConfig {
+    fn default() -> Self {
+        VersionsConfig {
+            enabled: true,
+            keep: 10,
+            max_age_days: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CompressionConfig {
+    /// Advertises and accepts MODE Z (deflate) on data connections.
+    pub enabled: bool,
+    /// zlib compression level, from 0 (none) to 9 (best).
+    pub level: u32,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            enabled: true,
+            level: 6,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct JailConfig {
+    /// `follow-inside-root`, `deny` or `follow-all`.
+    pub symlinks: SymlinkPolicy,
+}
+
+impl Default for JailConfig {
+    fn default() -> Self {
+        JailConfig {
+            symlinks: SymlinkPolicy::FollowInsideRoot,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StorageBackend {
+    /// Files in the server root on disk.
+    Local,
+    /// Files kept in memory, lost when the server stops.
+    Memory,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    /// `local` or `memory`.
+    pub backend: StorageBackend,
+    /// Stores identical content once, split into content-defined chunks.
+    pub dedup: bool,
+    /// Encrypts stored files when set.
+    pub encryption: Option<EncryptionConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EncryptionConfig {
+    /// File holding the 32-byte key, raw or as 64 hex digits. Keep it off the server disk.
+    pub key_file: PathBuf,
+    /// Also encrypts file and directory names.
+    #[serde(default)]
+    pub encrypt_names: bool,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            backend: StorageBackend::Local,
+            dedup: false,
+            encryption: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RecordingConfig {
+    /// Records the control channel of every session, for debugging with `replay`.
+    pub enabled: bool,
+    /// Directory of the recordings, one file per session.
+    pub dir: PathBuf,
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        RecordingConfig {
+            enabled: false,
+            dir: PathBuf::from(META_DIR).join("recordings"),
+        }
+    }
+}
+
+/// A `[[users]]` entry.
+#[derive(Debug, Clone, Deserialize)]
+pub struct UserConfig {
+    pub name: String,
+    pub password: String,
+}
+
+/// A `[[shares]]` entry: a directory on disk, seen by clients as `/<name>`. Shares are
+/// set up once at startup; only `users` follows config reloads.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ShareConfig {
+    pub name: String,
+    pub path: PathBuf,
+    /// Refuses uploads, deletions, renames and new directories.
+    #[serde(default)]
+    pub read_only: bool,
+    /// Users who can see the share. When empty, everyone can.
+    #[serde(default)]
+    pub users: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PassiveConfig {
+    /// Address advertised in PASV replies: `auto` for the address the client connected
+    /// to, or the public IPv4 address or host name of a server behind a NAT or in a
+    /// container. Host names are resolved on each PASV, so dynamic DNS works.
+    pub address: String,
+}
+
+impl Default for PassiveConfig {
+    fn default() -> Self {
+        PassiveConfig {
+            address: "auto".to_string(),
+        }
+    }
+}
+
+/// Guest access, for handing out a folder without creating accounts.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AnonymousConfig {
+    /// Lets `anonymous` (or `ftp`) log in with any password, read-only and inside `share`.
+    pub enabled: bool,
+    /// Name of the `[[shares]]` entry guests see.
+    pub share: String,
+    /// Guests connected at once. 0 means no limit.
+    pub max_connections: usize,
+    /// Download rate of each guest connection, in KiB/s. 0 means no limit.
+    pub rate_limit_kib: u64,
+}
+
+impl Default for AnonymousConfig {
+    fn default() -> Self {
+        AnonymousConfig {
+            enabled: false,
+            share: String::new(),
+            max_connections: 10,
+            rate_limit_kib: 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SecurityConfig {
+    /// Failed logins from one IP, or for one user, before a temporary ban. 0 never bans.
+    pub max_failures: u32,
+    /// Length of a ban, and time after which failures are forgotten.
+    pub ban_secs: u64,
+    /// Delay before answering the first failed login, doubled with each failure.
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+    /// CIDR ranges allowed to connect. When empty, every address is.
+    pub allow: Vec<Cidr>,
+    /// CIDR ranges refused, even if allowed.
+    pub deny: Vec<Cidr>,
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        SecurityConfig {
+            max_failures: 5,
+            ban_secs: 15 * 60,
+            base_delay_ms: 500,
+            max_delay_ms: 8000,
+            allow: Vec::new(),
+            deny: Vec::new(),
+        }
+    }
+}
+
+/// The admin API, read once at startup.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AdminConfig {
+    /// Address of the admin HTTP API, e.g. `127.0.0.1:2121`. Disabled when unset.
+    pub listen: Option<String>,
+    /// When set, requests must carry `Authorization: Bearer <token>`.
+    pub token: Option<String>,
+}
+
+/// A `[[hooks]]` entry, run after matching events. Set either `command` or `url`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HookConfig {
+    /// `stor`, `dele`, `rnto`, `mkd` and/or `rmd`. Empty means every event.
+    pub events: Vec<EventKind>,
+    /// Program and arguments, run with the event as JSON on stdin and in `VENTUS_*` variables.
+    pub command: Option<Vec<String>>,
+    /// `http://` URL the event is POSTed to as JSON.
+    pub url: Option<String>,
+    /// Seconds before the hook is killed or the request abandoned.
+    pub timeout_secs: u64,
+}
+
+impl Default for HookConfig {
+    fn default() -> Self {
+        HookConfig {
+            events: Vec::new(),
+            command: None,
+            url: None,
+            timeout_secs: 10,
+        }
+    }
+}
+
+impl Config {
+    /// Whether `user` may reach the virtual `path`: anything outside a share, or inside
+    /// one open to everyone or listing them.
+    pub fn may_access(&self, user: &str, path: &Path) -> bool {
+        let Some(top) = path.iter().nth(1) else {
+            return true;
+        };
+        self.shares
+            .iter()
+            .filter(|share| top == share.name.as_str())
+            .all(|share| share.users.is_empty() || share.users.iter().any(|name| name == user))
+    }
+
+    /// Refuses settings that only make sense together.
+    fn check(&self) -> Result<(), String> {
+        if self.anonymous.enabled {
+            // Without users, any name logs in with full access, so guests would be anything but.
+            if self.users.is_empty() {
+                return Err("anonymous access needs [[users]] entries".to_string());
+            }
+            if !self.shares.iter().any(|share| share.name == self.anonymous.share) {
+                return Err(format!("anonymous share \"{}\" isn't in [[shares]]", self.anonymous.share));
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads the config from `$VENTUS_CONFIG`, or `.ventus/config.toml` in the server root.
+    /// Falls back to the defaults when no config file exists.
+    pub fn load() -> Config {
+        Config::try_load().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `load`, but reports an invalid config instead of panicking.
+    pub fn try_load() -> Result<Config, String> {
+        let path = env::var_os("VENTUS_CONFIG")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(META_DIR).join("config.toml"));
+
+        match fs::read_to_string(&path) {
+            Ok(content) => {
+                let config: Config = toml::from_str(&content)
+                    .map_err(|e| format!("Invalid config {}: {}", path.display(), e))?;
+                config.check().map_err(|e| format!("Invalid config {}: {}", path.display(), e))?;
+                println!("[*] Loaded config from {}", path.display());
+                Ok(config)
+            }
+            Err(_) => Ok(Config::default()),
+        }
+    }
+}
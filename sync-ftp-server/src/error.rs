@@ -0,0 +1,80 @@
+use std::fmt;
+use std::io::{self, ErrorKind};
+
+use crate::command::ResultCode;
+use crate::jail::PathError;
+
+/// Why a command failed. Each error maps to the reply the client gets for it, so a
+/// failure ends the command, not the session.
+#[derive(Debug)]
+pub enum FtpError {
+    /// A client path the session may not use.
+    Path(PathError),
+    /// A storage, disk or network operation failed.
+    Io(io::Error),
+    /// The command needs a data connection, and none is open.
+    NoDataConnection,
+    /// A reply the command chose itself.
+    Reply(ResultCode, String),
+}
+
+pub type Result<T> = std::result::Result<T, FtpError>;
+
+impl FtpError {
+    pub fn reply(code: ResultCode, message: &str) -> FtpError {
+        FtpError::Reply(code, message.to_string())
+    }
+
+    pub fn code(&self) -> ResultCode {
+        match self {
+            FtpError::Path(_) => ResultCode::FileUnavailable,
+            FtpError::Io(e) => match e.kind() {
+                ErrorKind::NotFound
+                | ErrorKind::PermissionDenied
+                | ErrorKind::AlreadyExists
+                | ErrorKind::IsADirectory
+                | ErrorKind::NotADirectory
+                | ErrorKind::DirectoryNotEmpty => ResultCode::FileUnavailable,
+                ErrorKind::InvalidInput | ErrorKind::InvalidFilename => ResultCode::FileNameNotAllowed,
+                ErrorKind::StorageFull | ErrorKind::QuotaExceeded | ErrorKind::FileTooLarge => ResultCode::InsufficientStorageSpace,
+                ErrorKind::Unsupported => ResultCode::CommandNotImplementedForThatParameter,
+                ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof => {
+                    ResultCode::ConnectionClosed
+                }
+                ErrorKind::WouldBlock | ErrorKind::TimedOut => ResultCode::FileActionNotTaken,
+                _ => ResultCode::LocalErrorInProcessing,
+            },
+            FtpError::NoDataConnection => ResultCode::CantOpenDataConnection,
+            FtpError::Reply(code, _) => *code,
+        }
+    }
+}
+
+impl fmt::Display for FtpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FtpError::Path(e) => write!(f, "{}", e),
+            FtpError::Io(e) => {
+                // The "(os error N)" suffix means nothing to the client.
+                let message = e.to_string();
+                write!(f, "{}", message.split(" (os error").next().unwrap_or_default())
+            }
+            FtpError::NoDataConnection => write!(f, "No data connection."),
+            FtpError::Reply(_, message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for FtpError {}
+
+impl From<io::Error> for FtpError {
+    fn from(e: io::Error) -> Self {
+        FtpError::Io(e)
+    }
+}
+
+impl From<PathError> for FtpError {
+    fn from(e: PathError) -> Self {
+        FtpError::Path(e)
+    }
+}
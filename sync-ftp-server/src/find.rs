@@ -0,0 +1,228 @@
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::storage::Metadata;
+
+/// Longest pattern accepted, in characters.
+const MAX_PATTERN: usize = 255;
+
+/// What SITE FIND looks for. Without wildcards, the pattern matches names containing it.
+/// Both ignore case.
+pub struct FindQuery {
+    pattern: Vec<char>,
+    glob: bool,
+    /// -a: include dotfiles.
+    pub all: bool,
+    larger: Option<u64>,
+    smaller: Option<u64>,
+    newer: Option<u64>,
+    older: Option<u64>,
+}
+
+impl FindQuery {
+    /// Parses `[-a] [-larger <bytes>] [-smaller <bytes>] [-newer <time>] [-older <time>]
+    /// <pattern>`, with times in Unix seconds.
+    pub fn parse(args: &str) -> Result<FindQuery, String> {
+        let mut query = FindQuery {
+            pattern: Vec::new(),
+            glob: false,
+            all: false,
+            larger: None,
+            smaller: None,
+            newer: None,
+            older: None,
+        };
+        let mut rest = args.trim();
+        while let Some(word) = rest.strip_prefix('-') {
+            let (word, tail) = word.split_once(char::is_whitespace).unwrap_or((word, ""));
+            rest = tail.trim_start();
+            if word == "a" {
+                query.all = true;
+                continue;
+            }
+            let (value, tail) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            let value = value.parse::<u64>().map_err(|_| format!("-{} needs a number", word))?;
+            rest = tail.trim_start();
+            match word {
+                "larger" => query.larger = Some(value),
+                "smaller" => query.smaller = Some(value),
+                "newer" => query.newer = Some(value),
+                "older" => query.older = Some(value),
+                _ => return Err(format!("Unknown option -{}", word)),
+            }
+        }
+        if rest.is_empty() {
+            return Err("Missing pattern".to_string());
+        }
+        if rest.chars().count() > MAX_PATTERN {
+            return Err(format!("Pattern longer than {} characters", MAX_PATTERN));
+        }
+        query.pattern = rest.to_lowercase().chars().collect();
+        query.glob = rest.contains(['*', '?', '[']);
+        Ok(query)
+    }
+
+    pub fn matches(&self, name: &str, metadata: &Metadata) -> bool {
+        let name: Vec<char> = name.to_lowercase().chars().collect();
+        let found = match self.glob {
+            true => glob_match(&self.pattern, &name),
+            false => self.pattern.is_empty() || name.windows(self.pattern.len()).any(|window| window == self.pattern),
+        };
+        let modified = metadata.modified.map(unix_secs);
+        let sized = self.larger.is_none() && self.smaller.is_none();
+        let dated = self.newer.is_none() && self.older.is_none();
+        // Size filters only apply to files, as directories have no meaningful size.
+        found
+            && (sized || !metadata.is_dir)
+            && self.larger.is_none_or(|larger| metadata.len > larger)
+            && self.smaller.is_none_or(|smaller| metadata.len < smaller)
+            && (dated || modified.is_some())
+            && self.newer.is_none_or(|newer| modified.is_some_and(|modified| modified > newer))
+            && self.older.is_none_or(|older| modified.is_some_and(|modified| modified < older))
+    }
+}
+
+/// Matches `*` (any run of characters), `?` (one character) and `[...]` classes, with
+/// ranges and `!` negation.
+///
+/// On a mismatch, only the last `*` is retried one character further, which is enough
+/// as it can absorb whatever earlier stars would have: O(pattern × name), never worse.
+fn glob_match(pattern: &[char], name: &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // Pattern position after the last `*`, and the name position it's retried from.
+    let mut star = None;
+    while n < name.len() {
+        if pattern.get(p) == Some(&'*') {
+            p += 1;
+            star = Some((p, n));
+            continue;
+        }
+        if p < pattern.len() {
+            let (matched, len) = match_one(&pattern[p..], name[n]);
+            if matched {
+                p += len;
+                n += 1;
+                continue;
+            }
+        }
+        let Some((star_p, star_n)) = star else {
+            return false;
+        };
+        p = star_p;
+        n = star_n + 1;
+        star = Some((star_p, n));
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Whether the item (not `*`) at the start of `pattern` matches `c`, and its length.
+fn match_one(pattern: &[char], c: char) -> (bool, usize) {
+    match pattern[0] {
+        '?' => (true, 1),
+        '[' => {
+            // A `]` right after `[` or `[!` is a member, not the end.
+            let Some(end) = pattern.iter().skip(2).position(|&c| c == ']').map(|end| end + 2) else {
+                // No closing bracket: a literal `[`.
+                return (c == '[', 1);
+            };
+            let (negated, class) = match pattern[1] {
+                '!' | '^' => (true, &pattern[2..end]),
+                _ => (false, &pattern[1..end]),
+            };
+            let mut found = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == '-' {
+                    found |= (class[i]..=class[i + 2]).contains(&c);
+                    i += 3;
+                } else {
+                    found |= class[i] == c;
+                    i += 1;
+                }
+            }
+            (found != negated, end + 1)
+        }
+        literal => (literal == c, 1),
+    }
+}
+
+/// An MLSD-style line (RFC 3659): `type=...;size=...;modify=...; path`.
+pub fn facts(path: &Path, metadata: &Metadata) -> String {
+    let mut line = format!("type={};size={};", if metadata.is_dir { "dir" } else { "file" }, metadata.len);
+    if let Some(modified) = metadata.modified {
+        line.push_str(&format!("modify={};", mlsd_time(unix_secs(modified))));
+    }
+    format!("{} {}", line, path.display())
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+}
+
+/// `YYYYMMDDHHMMSS` in UTC.
+fn mlsd_time(secs: u64) -> String {
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Civil date from days since 1970-01-01, after Howard Hinnant's `civil_from_days`.
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}{:02}{:02}{:02}{:02}{:02}", year, month, day, secs / 3600, secs % 3600 / 60, secs % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(pattern: &str, name: &str) -> bool {
+        glob_match(&pattern.chars().collect::<Vec<_>>(), &name.chars().collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn glob_wildcards() {
+        assert!(glob("*.txt", "notes.txt"));
+        assert!(glob("*", ""));
+        assert!(!glob("*.txt", "notes.txt.bak"));
+        assert!(glob("a*b*c", "axxbyyc"));
+        assert!(glob("n?te", "note"));
+        assert!(!glob("n?te", "nte"));
+        assert!(glob("ünï*", "ünïcode"));
+    }
+
+    #[test]
+    fn glob_classes() {
+        assert!(glob("[abc]1", "b1"));
+        assert!(glob("[a-c]1", "c1"));
+        assert!(!glob("[a-c]1", "d1"));
+        assert!(glob("[!a-c]1", "d1"));
+        assert!(glob("[^a-c]1", "d1"));
+        assert!(glob("[]]", "]"));
+        // Without a closing bracket, `[` is a literal.
+        assert!(glob("[a", "[a"));
+        assert!(!glob("[a", "a"));
+    }
+
+    #[test]
+    fn glob_backtracking_stays_linear() {
+        let name = "a".repeat(200);
+        assert!(!glob(&format!("{}b", "*a".repeat(100)), &name));
+        assert!(glob(&"*a".repeat(100), &name));
+    }
+
+    #[test]
+    fn query_options_and_limits() {
+        let query = FindQuery::parse("-a -larger 10 *.LOG").unwrap();
+        assert!(query.all);
+        let file = |len| Metadata { is_dir: false, len, modified: None };
+        assert!(query.matches("server.log", &file(11)));
+        assert!(!query.matches("server.log", &file(10)));
+        assert!(FindQuery::parse("-larger x *.log").is_err());
+        assert!(FindQuery::parse("-a").is_err());
+        assert!(FindQuery::parse(&"x".repeat(MAX_PATTERN + 1)).is_err());
+    }
+}
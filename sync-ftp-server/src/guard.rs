@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::config::SecurityConfig;
+
+/// An address range such as `192.168.0.0/16` or `fd00::/8`. A bare address matches
+/// only itself.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (addr, prefix) = value.split_once('/').unwrap_or((&value, ""));
+        let addr: IpAddr = addr.parse().map_err(|_| format!("invalid address in {}", value))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            "" => max,
+            prefix => prefix.parse().ok().filter(|&prefix| prefix <= max).ok_or(format!("invalid prefix in {}", value))?,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Refusal {
+    /// The address is outside the allow list, or inside the deny list.
+    Denied,
+    /// Too many failed logins; banned for the given time.
+    Banned(Duration),
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Refusal::Denied => write!(f, "Connections from your address are not allowed"),
+            Refusal::Banned(left) => write!(f, "Too many failed logins, try again in {}s", left.as_secs() + 1),
+        }
+    }
+}
+
+struct Failures {
+    count: u32,
+    last: Instant,
+    banned_until: Option<Instant>,
+}
+
+/// Failed-login counters per IP and per user name, with exponential delays and
+/// temporary bans. Counters are forgotten after `ban_secs` without failures.
+#[derive(Default)]
+pub struct LoginGuard {
+    ips: Mutex<HashMap<IpAddr, Failures>>,
+    users: Mutex<HashMap<String, Failures>>,
+}
+
+fn banned<K: Eq + Hash>(map: &Mutex<HashMap<K, Failures>>, key: &K) -> Option<Duration> {
+    let now = Instant::now();
+    map.lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(key)
+        .and_then(|failures| failures.banned_until)
+        .filter(|&until| until > now)
+        .map(|until| until - now)
+}
+
+/// Counts a failure, banning the key when it reaches the limit. Returns the count, and
+/// whether the key just got banned.
+fn count<K: Eq + Hash>(map: &Mutex<HashMap<K, Failures>>, key: K, config: &SecurityConfig) -> (u32, bool) {
+    let now = Instant::now();
+    let window = Duration::from_secs(config.ban_secs);
+    let mut map = map.lock().unwrap_or_else(PoisonError::into_inner);
+    map.retain(|_, failures| now.duration_since(failures.last) < window || failures.banned_until.is_some_and(|until| until > now));
+
+    let failures = map.entry(key).or_insert(Failures { count: 0, last: now, banned_until: None });
+    if failures.banned_until.is_some_and(|until| until <= now) {
+        failures.count = 0;
+        failures.banned_until = None;
+    }
+    failures.count += 1;
+    failures.last = now;
+    let ban = config.max_failures > 0 && failures.count >= config.max_failures;
+    if ban {
+        failures.banned_until = Some(now + window);
+    }
+    (failures.count, ban)
+}
+
+impl LoginGuard {
+    /// Whether a connection from `ip` may be accepted.
+    pub fn admit(&self, ip: IpAddr, config: &SecurityConfig) -> Result<(), Refusal> {
+        let allowed = config.allow.is_empty() || config.allow.iter().any(|cidr| cidr.contains(ip));
+        if !allowed || config.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return Err(Refusal::Denied);
+        }
+        match banned(&self.ips, &ip) {
+            Some(left) => Err(Refusal::Banned(left)),
+            None => Ok(()),
+        }
+    }
+
+    /// Time left on the ban of `user`, if any.
+    pub fn user_banned(&self, user: &str) -> Option<Duration> {
+        banned(&self.users, &user.to_string())
+    }
+
+    /// Records a failed login. Returns the delay to wait before answering, and whether
+    /// the IP is now banned.
+    pub fn failure(&self, ip: IpAddr, user: &str, config: &SecurityConfig) -> (Duration, bool) {
+        let (ip_count, ip_banned) = count(&self.ips, ip, config);
+        let (user_count, user_banned) = count(&self.users, user.to_string(), config);
+        if ip_banned {
+            println!("[!] Banned {} for {} seconds after {} failed logins", ip, config.ban_secs, ip_count);
+        }
+        if user_banned {
+            println!("[!] Locked user {} for {} seconds after {} failed logins", user, config.ban_secs, user_count);
+        }
+
+        let exponent = ip_count.max(user_count).saturating_sub(1).min(16);
+        let delay = config.base_delay_ms.saturating_mul(1 << exponent).min(config.max_delay_ms);
+        (Duration::from_millis(delay), ip_banned)
+    }
+
+    /// Forgets the failures of `user`. Those of the IP stay, so logging in to one account
+    /// doesn't make up for guessing the passwords of others.
+    pub fn success(&self, user: &str) {
+        self.users.lock().unwrap_or_else(PoisonError::into_inner).remove(user);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(value: &str) -> Cidr {
+        Cidr::try_from(value.to_string()).unwrap()
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_contains_ipv4() {
+        let net = cidr("192.168.1.0/24");
+        assert!(net.contains(ip("192.168.1.0")));
+        assert!(net.contains(ip("192.168.1.255")));
+        assert!(!net.contains(ip("192.168.2.1")));
+        assert!(cidr("0.0.0.0/0").contains(ip("8.8.8.8")));
+        assert!(cidr("10.0.0.1").contains(ip("10.0.0.1")));
+        assert!(!cidr("10.0.0.1").contains(ip("10.0.0.2")));
+    }
+
+    #[test]
+    fn cidr_contains_ipv6_and_mapped_ipv4() {
+        let net = cidr("fd00::/8");
+        assert!(net.contains(ip("fd12:3456::1")));
+        assert!(!net.contains(ip("fe80::1")));
+        assert!(!net.contains(ip("10.0.0.1")));
+        assert!(cidr("127.0.0.0/8").contains(ip("::ffff:127.0.0.1")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("::1")));
+    }
+
+    #[test]
+    fn cidr_rejects_invalid_ranges() {
+        for value in ["10.0.0.0/33", "::/129", "10.0.0.0/x", "nonsense", ""] {
+            assert!(Cidr::try_from(value.to_string()).is_err(), "{}", value);
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::HookConfig;
+use crate::storage::{self, Storage};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Stor,
+    Dele,
+    Rnto,
+    Mkd,
+    Rmd,
+}
+
+impl EventKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            EventKind::Stor => "stor",
+            EventKind::Dele => "dele",
+            EventKind::Rnto => "rnto",
+            EventKind::Mkd => "mkd",
+            EventKind::Rmd => "rmd",
+        }
+    }
+}
+
+/// What a hook receives, as JSON.
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub event: EventKind,
+    pub user: String,
+    pub path: PathBuf,
+    /// Previous path, for RNTO.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<PathBuf>,
+    pub size: Option<u64>,
+    /// SHA-256 of the stored file, for STOR.
+    pub hash: Option<String>,
+}
+
+impl Event {
+    pub fn new(event: EventKind, user: &str, path: PathBuf) -> Event {
+        Event {
+            event,
+            user: user.to_string(),
+            path,
+            from: None,
+            size: None,
+            hash: None,
+        }
+    }
+}
+
+/// Runs the hooks subscribed to `event`, each in its own thread so the session
+/// never waits for them. Failures are only logged.
+pub fn fire(hooks: &[HookConfig], storage: &Arc<dyn Storage>, mut event: Event) {
+    let hooks: Vec<HookConfig> = hooks
+        .iter()
+        .filter(|hook| hook.events.is_empty() || hook.events.contains(&event.event))
+        .cloned()
+        .collect();
+    if hooks.is_empty() {
+        return;
+    }
+
+    let storage = Arc::clone(storage);
+    thread::spawn(move || {
+        if event.event == EventKind::Stor {
+            match storage::sha256(&*storage, &event.path) {
+                Ok(hash) => event.hash = Some(hash),
+                Err(e) => println!("[!] Couldn't hash {} for hooks: {}", event.path.display(), e),
+            }
+        }
+        let payload = serde_json::to_string(&event).unwrap_or_default();
+
+        for hook in hooks {
+            let payload = payload.clone();
+            let event = event.clone();
+            thread::spawn(move || {
+                let timeout = Duration::from_secs(hook.timeout_secs.max(1));
+                let result = match (&hook.command, &hook.url) {
+                    (Some(command), _) => run_command(command, &event, &payload, timeout),
+                    (None, Some(url)) => post(url, &payload, timeout),
+                    (None, None) => Err(Error::new(ErrorKind::InvalidInput, "Hook has neither a command nor a url")),
+                };
+                if let Err(e) = result {
+                    println!("[!] Hook for {} {} failed: {}", event.event.as_str(), event.path.display(), e);
+                }
+            });
+        }
+    });
+}
+
+/// Runs `command` with the event in `VENTUS_*` variables and as JSON on stdin.
+fn run_command(command: &[String], event: &Event, payload: &str, timeout: Duration) -> Result<()> {
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Empty hook command"))?;
+    let mut child = Command::new(program)
+        .args(args)
+        .env("VENTUS_EVENT", event.event.as_str())
+        .env("VENTUS_USER", &event.user)
+        .env("VENTUS_PATH", &event.path)
+        .env("VENTUS_SIZE", event.size.map(|size| size.to_string()).unwrap_or_default())
+        .env("VENTUS_HASH", event.hash.as_deref().unwrap_or_default())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // A hook that doesn't read its input is fine.
+        let _ = stdin.write_all(payload.as_bytes());
+    }
+
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            if status.success() {
+                return Ok(());
+            }
+            return Err(Error::other(format!("{} exited with {}", program, status)));
+        }
+        if start.elapsed() >= timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(Error::new(ErrorKind::TimedOut, format!("{} timed out", program)));
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// POSTs the JSON payload to a plain `http://` URL and expects a 2xx status.
+fn post(url: &str, payload: &str, timeout: Duration) -> Result<()> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Only http:// hook URLs are supported"))?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let addr = if host.contains(':') { host.to_string() } else { format!("{}:80", host) };
+    let addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Couldn't resolve {}", host)))?;
+
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        payload.len(),
+        payload
+    )?;
+
+    let mut status = [0u8; 12];
+    stream.read_exact(&mut status)?;
+    let status = String::from_utf8_lossy(&status);
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(Error::other(format!("{} answered {}", url, status.trim()))),
+    }
+}
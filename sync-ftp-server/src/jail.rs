@@ -0,0 +1,174 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::config::META_DIR;
+
+/// What to do with symlinks met while resolving a client path.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SymlinkPolicy {
+    /// Follow symlinks whose target stays inside the server root.
+    FollowInsideRoot,
+    /// Refuse any path going through a symlink.
+    Deny,
+    /// Follow every symlink, wherever it points.
+    FollowAll,
+}
+
+#[derive(Debug)]
+pub enum PathError {
+    /// The path names the server's private metadata directory.
+    Reserved,
+    /// The path goes through a symlink and symlinks are denied.
+    Symlink,
+    /// A symlink on the path points outside the server root, or nowhere.
+    OutsideRoot,
+    /// The path is in a share the user isn't allowed to see.
+    Hidden,
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PathError::Reserved => write!(f, "Permission denied"),
+            PathError::Symlink => write!(f, "Permission denied (symlink)"),
+            PathError::OutsideRoot => write!(f, "Permission denied (outside the server root)"),
+            PathError::Hidden => write!(f, "No such file or directory"),
+        }
+    }
+}
+
+impl std::error::Error for PathError {}
+
+impl From<PathError> for io::Error {
+    fn from(e: PathError) -> Self {
+        io::Error::new(io::ErrorKind::PermissionDenied, e)
+    }
+}
+
+/// Normalizes a client `path`, absolute or relative to the virtual `cwd`, without touching
+/// the disk, so `..` can never climb above `/` whether the target exists or not.
+/// Paths into the server's private metadata directory are refused.
+pub fn virtual_path(cwd: &Path, path: &Path) -> Result<PathBuf, PathError> {
+    let mut normalized = PathBuf::from("/");
+    for component in cwd.join(path).components() {
+        match component {
+            Component::RootDir | Component::Prefix(_) => normalized = PathBuf::from("/"),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::Normal(name) => normalized.push(name),
+        }
+    }
+
+    if normalized.iter().nth(1).is_some_and(|name| name == META_DIR) {
+        return Err(PathError::Reserved);
+    }
+    Ok(normalized)
+}
+
+/// Maps virtual paths onto a directory on disk, walking them component by component
+/// to apply the symlink policy.
+pub struct Jail {
+    root: PathBuf,
+    policy: SymlinkPolicy,
+}
+
+impl Jail {
+    pub fn new(root: &Path, policy: SymlinkPolicy) -> Jail {
+        Jail {
+            root: root.canonicalize().unwrap_or_else(|_| root.to_path_buf()),
+            policy,
+        }
+    }
+
+    /// Real location of the virtual `path`, following a symlink in the last component as
+    /// allowed by the policy.
+    pub fn resolve(&self, path: &Path) -> Result<PathBuf, PathError> {
+        self.resolve_with(path, true)
+    }
+
+    /// Like `resolve`, but without following the last component, for operations on the
+    /// entry itself (delete, rename).
+    pub fn resolve_entry(&self, path: &Path) -> Result<PathBuf, PathError> {
+        self.resolve_with(path, false)
+    }
+
+    fn resolve_with(&self, path: &Path, follow_last: bool) -> Result<PathBuf, PathError> {
+        let names: Vec<_> = path
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(name),
+                _ => None,
+            })
+            .collect();
+
+        let mut real = self.root.clone();
+        for (i, name) in names.iter().enumerate() {
+            real.push(name);
+            if i + 1 == names.len() && !follow_last {
+                break;
+            }
+            let Ok(metadata) = fs::symlink_metadata(&real) else {
+                // Nothing exists from here on, so no symlink can be met anymore.
+                real.extend(&names[i + 1..]);
+                break;
+            };
+            if metadata.file_type().is_symlink() {
+                real = self.follow(&real)?;
+            }
+        }
+
+        Ok(real)
+    }
+
+    fn follow(&self, link: &Path) -> Result<PathBuf, PathError> {
+        match self.policy {
+            SymlinkPolicy::Deny => {
+                println!("[!] Refused symlink {}", link.display());
+                Err(PathError::Symlink)
+            }
+            SymlinkPolicy::FollowAll => Ok(link.canonicalize().unwrap_or_else(|_| link.to_path_buf())),
+            SymlinkPolicy::FollowInsideRoot => {
+                let inside = link.canonicalize().ok().filter(|target| {
+                    target
+                        .strip_prefix(&self.root)
+                        .is_ok_and(|relative| !relative.starts_with(META_DIR))
+                });
+                inside.ok_or_else(|| {
+                    println!("[!] Refused symlink {} leading outside the server root", link.display());
+                    PathError::OutsideRoot
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn virtual_path_never_climbs_above_the_root() {
+        let cwd = Path::new("/docs");
+        assert_eq!(virtual_path(cwd, Path::new("../../../etc/passwd")).unwrap(), Path::new("/etc/passwd"));
+        assert_eq!(virtual_path(cwd, Path::new("a/./b/..")).unwrap(), Path::new("/docs/a"));
+        assert_eq!(virtual_path(cwd, Path::new("/other")).unwrap(), Path::new("/other"));
+        assert_eq!(virtual_path(Path::new("/"), Path::new("..")).unwrap(), Path::new("/"));
+    }
+
+    #[test]
+    fn virtual_path_refuses_the_metadata_directory() {
+        let root = Path::new("/");
+        assert!(matches!(virtual_path(root, Path::new(".ventus")), Err(PathError::Reserved)));
+        assert!(matches!(virtual_path(root, Path::new("./.ventus/config.toml")), Err(PathError::Reserved)));
+        assert!(matches!(virtual_path(Path::new("/docs"), Path::new("../.ventus")), Err(PathError::Reserved)));
+        // Only the top-level one is private.
+        assert!(virtual_path(root, Path::new("docs/.ventus")).is_ok());
+    }
+}
@@ -1,9 +1,25 @@
 use colored::Colorize;
 use std::net::TcpListener;
+use std::sync::Arc;
 use std::thread;
+mod admin;
+mod changes;
 mod client;
 mod command;
+mod config;
+mod error;
+mod find;
+mod guard;
+mod hooks;
+mod jail;
+mod recorder;
+mod sessions;
+mod state;
+mod storage;
+mod transfer;
+mod trash;
 mod utils;
+mod versions;
 use std::process::Command;
 use std::string::ToString;
 
@@ -47,6 +63,13 @@ fn main() {
                                                 .+@*.
 "#;
     println!("{}", ascii.purple());
+
+    let config = config::Config::load();
+    let admin = config.admin.clone();
+    let state = Arc::new(state::ServerState::new(config));
+    if let Some(listen) = &admin.listen {
+        admin::serve(Arc::clone(&state), listen, admin.token.clone());
+    }
     
     
    
@@ -61,9 +84,23 @@ fn main() {
     println!("\n[*] Waiting for clients to connect...");
     
     for stream in listener.incoming() {
-        if let Ok(stream) = stream {
+        if let Ok(mut stream) = stream {
+            // Without an address, the allow and deny lists can't be checked.
+            let addr = match stream.peer_addr() {
+                Ok(addr) => addr,
+                Err(e) => {
+                    println!("[!] Refused connection from an unknown address: {}", e);
+                    continue;
+                }
+            };
+            if let Err(refusal) = state.guard.admit(addr.ip(), &state.config().security) {
+                println!("[!] Refused connection from {}: {}", addr.ip(), refusal);
+                utils::send_cmd(&mut stream, command::ResultCode::ServiceNotAvailable, &refusal.to_string());
+                continue;
+            }
+            let state = Arc::clone(&state);
             thread::spawn(move || {
-                client::Client::handle_client(stream);
+                client::Client::handle_client(stream, state);
             });
         } else {
             println!("[*] A client tried to connect...");
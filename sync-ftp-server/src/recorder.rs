@@ -0,0 +1,112 @@
+use std::fs::{create_dir_all, File};
+use std::io::{LineWriter, Read, Result, Write};
+use std::net::TcpStream;
+use std::ops::Deref;
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Writes the control channel of one session to a text file, one line per command
+/// received (`C`) or reply line sent (`S`), prefixed by milliseconds since the
+/// session started:
+///
+/// ```text
+/// # ventus session 3 from 127.0.0.1:50412 at 1700000000
+/// 0 S 220 Welcome to this FTP server!
+/// 12 C USER bob
+/// 12 S 230 Welcome bob
+/// ```
+///
+/// The `replay` binary drives a server with such a file.
+pub struct Recorder {
+    file: LineWriter<File>,
+    start: Instant,
+}
+
+impl Recorder {
+    pub fn create(dir: &Path, id: u64, stream: &TcpStream) -> Result<Recorder> {
+        create_dir_all(dir)?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let file = File::create(dir.join(format!("{}-{}.txt", now, id)))?;
+        let mut recorder = Recorder {
+            file: LineWriter::new(file),
+            start: Instant::now(),
+        };
+        let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+        writeln!(recorder.file, "# ventus session {} from {} at {}", id, peer, now)?;
+        Ok(recorder)
+    }
+
+    fn record(&mut self, direction: char, line: &str) {
+        let elapsed = self.start.elapsed().as_millis();
+        if let Err(e) = writeln!(self.file, "{} {} {}", elapsed, direction, line) {
+            println!("[!] Couldn't record session: {}", e);
+        }
+    }
+
+    /// Records a received command. Passwords are masked.
+    pub fn command(&mut self, command: &[u8]) {
+        let command = String::from_utf8_lossy(command);
+        // Compared as bytes: the 5th byte may fall inside a multibyte character.
+        if command.as_bytes().get(..5).is_some_and(|prefix| prefix.eq_ignore_ascii_case(b"PASS ")) {
+            self.record('C', "PASS ****");
+        } else {
+            self.record('C', &command);
+        }
+    }
+}
+
+/// The control connection, recording what goes through it when a recorder is set.
+pub struct RecordingStream {
+    stream: TcpStream,
+    recorder: Option<Recorder>,
+    /// Reply bytes not yet ended by a line break.
+    pending: Vec<u8>,
+}
+
+impl RecordingStream {
+    pub fn new(stream: TcpStream, recorder: Option<Recorder>) -> RecordingStream {
+        RecordingStream {
+            stream,
+            recorder,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn record_command(&mut self, command: &[u8]) {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.command(command);
+        }
+    }
+}
+
+impl Deref for RecordingStream {
+    type Target = TcpStream;
+
+    fn deref(&self) -> &TcpStream {
+        &self.stream
+    }
+}
+
+impl Read for RecordingStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for RecordingStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let n = self.stream.write(buf)?;
+        if let Some(recorder) = &mut self.recorder {
+            self.pending.extend_from_slice(&buf[..n]);
+            while let Some(end) = self.pending.windows(2).position(|w| w == b"\r\n") {
+                recorder.record('S', &String::from_utf8_lossy(&self.pending[..end]));
+                self.pending.drain(..end + 2);
+            }
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.stream.flush()
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Shutdown, TcpStream};
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+/// Registry of connected clients, and of users disabled at runtime.
+#[derive(Default)]
+pub struct Sessions {
+    sessions: Mutex<HashMap<u64, Arc<Session>>>,
+    disabled: Mutex<HashSet<String>>,
+    next_id: AtomicU64,
+    /// Anonymous guests logged in.
+    guests: AtomicUsize,
+}
+
+pub struct Session {
+    pub id: u64,
+    ip: Option<IpAddr>,
+    connected: u64,
+    /// Clones of the control and data connections, shut down to kick the client.
+    control: Option<TcpStream>,
+    data: Mutex<Option<TcpStream>>,
+    info: Mutex<SessionInfo>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SessionInfo {
+    pub user: Option<String>,
+    pub cwd: PathBuf,
+    pub transfer: Option<Transfer>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Transfer {
+    /// `STOR` or `RETR`.
+    pub command: &'static str,
+    pub path: PathBuf,
+    pub bytes: u64,
+    /// Total size, when known.
+    pub size: Option<u64>,
+}
+
+/// What the admin API shows of a session.
+#[derive(Debug, Serialize)]
+pub struct SessionSnapshot {
+    pub id: u64,
+    pub ip: Option<IpAddr>,
+    pub connected: u64,
+    pub user: Option<String>,
+    pub cwd: PathBuf,
+    pub transfer: Option<Transfer>,
+}
+
+/// Unregisters its session when the client thread ends, even by panicking.
+pub struct Registration {
+    sessions: Arc<Sessions>,
+    pub session: Arc<Session>,
+}
+
+impl Deref for Registration {
+    type Target = Session;
+
+    fn deref(&self) -> &Session {
+        &self.session
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.sessions.sessions.lock().unwrap_or_else(PoisonError::into_inner).remove(&self.session.id);
+    }
+}
+
+/// Counts an anonymous guest until dropped, when the guest logs out or disconnects.
+pub struct GuestPass {
+    sessions: Arc<Sessions>,
+}
+
+impl Drop for GuestPass {
+    fn drop(&mut self) {
+        self.sessions.guests.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Sessions {
+    /// Admits one more guest, unless `max` (0 for no limit) are already logged in.
+    pub fn admit_guest(self: &Arc<Self>, max: usize) -> Option<GuestPass> {
+        self.guests
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| (max == 0 || count < max).then_some(count + 1))
+            .ok()
+            .map(|_| GuestPass { sessions: Arc::clone(self) })
+    }
+
+    pub fn register(self: &Arc<Self>, stream: &TcpStream) -> Registration {
+        let session = Arc::new(Session {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+            ip: stream.peer_addr().ok().map(|addr| addr.ip()),
+            connected: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            control: stream.try_clone().ok(),
+            data: Mutex::new(None),
+            info: Mutex::new(SessionInfo {
+                cwd: PathBuf::from("/"),
+                ..SessionInfo::default()
+            }),
+        });
+        self.sessions.lock().unwrap_or_else(PoisonError::into_inner).insert(session.id, Arc::clone(&session));
+        Registration {
+            sessions: Arc::clone(self),
+            session,
+        }
+    }
+
+    pub fn list(&self) -> Vec<SessionSnapshot> {
+        let mut list: Vec<SessionSnapshot> = self
+            .sessions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .map(|session| {
+                let info = session.info();
+                SessionSnapshot {
+                    id: session.id,
+                    ip: session.ip,
+                    connected: session.connected,
+                    user: info.user,
+                    cwd: info.cwd,
+                    transfer: info.transfer,
+                }
+            })
+            .collect();
+        list.sort_by_key(|session| session.id);
+        list
+    }
+
+    /// Disconnects a session. Returns whether it existed.
+    pub fn kick(&self, id: u64) -> bool {
+        match self.sessions.lock().unwrap_or_else(PoisonError::into_inner).get(&id) {
+            Some(session) => {
+                session.kick();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Refuses further logins of `user` and disconnects its sessions.
+    /// Returns the number of sessions kicked.
+    pub fn disable(&self, user: &str) -> usize {
+        self.disabled.lock().unwrap_or_else(PoisonError::into_inner).insert(user.to_string());
+        let sessions = self.sessions.lock().unwrap_or_else(PoisonError::into_inner);
+        let kicked: Vec<&Arc<Session>> = sessions
+            .values()
+            .filter(|session| session.info().user.as_deref() == Some(user))
+            .collect();
+        for session in &kicked {
+            session.kick();
+        }
+        kicked.len()
+    }
+
+    /// Allows `user` again. Returns whether it was disabled.
+    pub fn enable(&self, user: &str) -> bool {
+        self.disabled.lock().unwrap_or_else(PoisonError::into_inner).remove(user)
+    }
+
+    pub fn is_disabled(&self, user: &str) -> bool {
+        self.disabled.lock().unwrap_or_else(PoisonError::into_inner).contains(user)
+    }
+
+    pub fn disabled(&self) -> Vec<String> {
+        let mut users: Vec<String> = self.disabled.lock().unwrap_or_else(PoisonError::into_inner).iter().cloned().collect();
+        users.sort();
+        users
+    }
+}
+
+impl Session {
+    pub fn info(&self) -> SessionInfo {
+        self.info.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    pub fn update(&self, update: impl FnOnce(&mut SessionInfo)) {
+        update(&mut self.info.lock().unwrap_or_else(PoisonError::into_inner));
+    }
+
+    pub fn start_transfer(&self, command: &'static str, path: PathBuf, size: Option<u64>) {
+        self.update(|info| info.transfer = Some(Transfer { command, path, bytes: 0, size }));
+    }
+
+    pub fn progress(&self, bytes: usize) {
+        self.update(|info| {
+            if let Some(transfer) = &mut info.transfer {
+                transfer.bytes += bytes as u64;
+            }
+        });
+    }
+
+    /// Remembers the data connection, so a kick also interrupts transfers.
+    pub fn set_data(&self, stream: Option<&TcpStream>) {
+        *self.data.lock().unwrap_or_else(PoisonError::into_inner) = stream.and_then(|stream| stream.try_clone().ok());
+    }
+
+    fn kick(&self) {
+        println!("[*] Kicking session {}", self.id);
+        if let Some(stream) = self.data.lock().unwrap_or_else(PoisonError::into_inner).take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        if let Some(stream) = &self.control {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use std::sync::{Arc, PoisonError, RwLock};
+
+use crate::changes::Changes;
+use crate::config::Config;
+use crate::guard::LoginGuard;
+use crate::sessions::Sessions;
+use crate::storage::{self, Storage};
+use crate::transfer::Segments;
+
+/// Everything the client threads and the admin API share.
+pub struct ServerState {
+    config: RwLock<Arc<Config>>,
+    pub storage: Arc<dyn Storage>,
+    pub sessions: Arc<Sessions>,
+    pub guard: LoginGuard,
+    pub changes: Changes,
+    pub segments: Segments,
+}
+
+impl ServerState {
+    pub fn new(config: Config) -> ServerState {
+        ServerState {
+            storage: storage::open(&config),
+            config: RwLock::new(Arc::new(config)),
+            sessions: Arc::new(Sessions::default()),
+            guard: LoginGuard::default(),
+            changes: Changes::new(),
+            segments: Segments::default(),
+        }
+    }
+
+    /// The current config. Sessions pick up a reloaded config on their next command.
+    pub fn config(&self) -> Arc<Config> {
+        Arc::clone(&self.config.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Reloads the config file. Storage and admin settings only change on restart.
+    pub fn reload_config(&self) -> Result<(), String> {
+        let config = Config::try_load()?;
+        *self.config.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(config);
+        println!("[*] Config reloaded");
+        Ok(())
+    }
+}
@@ -0,0 +1,396 @@
+use std::fs;
+use std::io::{Cursor, Error, ErrorKind, Read, Result, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use sha2::{Digest, Sha256};
+
+use super::{Entry, Metadata, Storage};
+use crate::config::META_DIR;
+
+const MAGIC: &[u8] = b"VENTUSE1";
+const HEADER_LEN: usize = MAGIC.len() + 16;
+/// Plaintext bytes per authenticated segment.
+const SEGMENT: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+
+/// Encrypting layer over another storage, with XChaCha20-Poly1305.
+///
+/// A file is a header (magic and a random 16-byte file nonce) followed by segments of up
+/// to 64 KiB, each sealed under a nonce made of the file nonce, the segment index and a
+/// last-segment flag, so segments can't be reordered, dropped or truncated unnoticed.
+///
+/// With name encryption, every path component except the top-level metadata directory
+/// is encrypted deterministically (the nonce is a keyed hash of the name), so paths can
+/// still be looked up. Equal names therefore have equal encrypted names.
+///
+/// Files that aren't encrypted can't be read, so it should be enabled on an empty root.
+pub struct EncryptedStorage {
+    inner: Arc<dyn Storage>,
+    contents: XChaCha20Poly1305,
+    names: Option<(XChaCha20Poly1305, [u8; 32])>,
+}
+
+/// Reads a 32-byte key, stored raw or as 64 hex digits.
+pub fn load_key(path: &Path) -> Result<[u8; 32]> {
+    let content = fs::read(path)?;
+    let text = String::from_utf8_lossy(&content);
+    let hex = text.trim();
+    if hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        let mut key = [0u8; 32];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap_or_default();
+        }
+        return Ok(key);
+    }
+
+    content
+        .try_into()
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "The key must be 32 bytes, or 64 hex digits"))
+}
+
+/// Derives a subkey, so contents and names never share a key.
+fn derive(key: &[u8; 32], purpose: &str) -> [u8; 32] {
+    Sha256::new().chain_update(purpose).chain_update(key).finalize().into()
+}
+
+fn nonce(file_nonce: &[u8; 16], counter: u64, last: bool) -> XNonce {
+    let mut nonce = [0u8; 24];
+    nonce[..16].copy_from_slice(file_nonce);
+    nonce[16..23].copy_from_slice(&counter.to_be_bytes()[1..]);
+    nonce[23] = last as u8;
+    nonce.into()
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Plaintext size of an encrypted file of `len` bytes.
+fn plain_len(len: u64) -> u64 {
+    let sealed = len.saturating_sub(HEADER_LEN as u64);
+    let segments = sealed.div_ceil((SEGMENT + TAG_LEN) as u64).max(1);
+    sealed.saturating_sub(segments * TAG_LEN as u64)
+}
+
+impl EncryptedStorage {
+    pub fn new(inner: Arc<dyn Storage>, key: &[u8; 32], encrypt_names: bool) -> EncryptedStorage {
+        let names = encrypt_names.then(|| {
+            let key = derive(key, "ventus names");
+            (XChaCha20Poly1305::new(&key.into()), derive(&key, "ventus name nonces"))
+        });
+        EncryptedStorage {
+            inner,
+            contents: XChaCha20Poly1305::new(&derive(key, "ventus contents").into()),
+            names,
+        }
+    }
+
+    fn real_path(&self, path: &Path) -> PathBuf {
+        let Some((cipher, nonce_key)) = &self.names else {
+            return path.to_path_buf();
+        };
+
+        let mut real = PathBuf::from("/");
+        for component in path.components() {
+            let Component::Normal(name) = component else {
+                continue;
+            };
+            if real == Path::new("/") && name == META_DIR {
+                real.push(name);
+                continue;
+            }
+
+            let name = name.to_string_lossy();
+            let digest = Sha256::new().chain_update(nonce_key).chain_update(name.as_bytes()).finalize();
+            let mut nonce = [0u8; 24];
+            nonce[..16].copy_from_slice(&digest[..16]);
+            let sealed = cipher.encrypt(&nonce.into(), name.as_bytes()).unwrap_or_default();
+            real.push(URL_SAFE_NO_PAD.encode([&nonce[..16], &sealed[..]].concat()));
+        }
+        real
+    }
+
+    /// Decrypted name of an entry of `dir`, `None` if it isn't one of ours.
+    fn plain_name(&self, dir: &Path, name: &str) -> Option<String> {
+        let Some((cipher, _)) = &self.names else {
+            return Some(name.to_string());
+        };
+        if dir == Path::new("/") && name == META_DIR {
+            return Some(name.to_string());
+        }
+
+        let encoded = URL_SAFE_NO_PAD.decode(name).ok()?;
+        if encoded.len() < 16 + TAG_LEN {
+            return None;
+        }
+        let mut nonce = [0u8; 24];
+        nonce[..16].copy_from_slice(&encoded[..16]);
+        let plain = cipher.decrypt(&nonce.into(), &encoded[16..]).ok()?;
+        String::from_utf8(plain).ok()
+    }
+}
+
+impl Storage for EncryptedStorage {
+    fn list(&self, path: &Path) -> Result<Vec<Entry>> {
+        let entries = self.inner.list(&self.real_path(path))?;
+        Ok(entries
+            .into_iter()
+            .filter_map(|entry| {
+                let name = self.plain_name(path, &entry.name)?;
+                let mut metadata = entry.metadata;
+                if !metadata.is_dir {
+                    metadata.len = plain_len(metadata.len);
+                }
+                Some(Entry { name, metadata })
+            })
+            .collect())
+    }
+
+    fn stat(&self, path: &Path) -> Result<Metadata> {
+        let mut metadata = self.inner.stat(&self.real_path(path))?;
+        if !metadata.is_dir {
+            metadata.len = plain_len(metadata.len);
+        }
+        Ok(metadata)
+    }
+
+    fn read(&self, path: &Path) -> Result<Box<dyn Read + Send>> {
+        let mut inner = self.inner.read(&self.real_path(path))?;
+        let mut header = [0u8; HEADER_LEN];
+        inner
+            .read_exact(&mut header)
+            .map_err(|_| invalid("Not an encrypted file"))?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(invalid("Not an encrypted file"));
+        }
+
+        Ok(Box::new(EncryptedReader {
+            cipher: self.contents.clone(),
+            inner,
+            file_nonce: header[MAGIC.len()..].try_into().unwrap_or_default(),
+            counter: 0,
+            ahead: Vec::new(),
+            plain: Cursor::new(Vec::new()),
+            done: false,
+        }))
+    }
+
+    fn write(&self, path: &Path) -> Result<Box<dyn Write + Send>> {
+        let mut file_nonce = [0u8; 16];
+        OsRng.fill_bytes(&mut file_nonce);
+
+        let mut inner = self.inner.write(&self.real_path(path))?;
+        inner.write_all(MAGIC)?;
+        inner.write_all(&file_nonce)?;
+
+        Ok(Box::new(EncryptedWriter {
+            cipher: self.contents.clone(),
+            inner,
+            file_nonce,
+            counter: 0,
+            buffer: Vec::new(),
+            finished: false,
+        }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        self.inner.rename(&self.real_path(from), &self.real_path(to))
+    }
+
+    fn delete(&self, path: &Path) -> Result<()> {
+        self.inner.delete(&self.real_path(path))
+    }
+
+    fn mkdir(&self, path: &Path) -> Result<()> {
+        self.inner.mkdir(&self.real_path(path))
+    }
+
+    fn available(&self, path: &Path) -> Result<u64> {
+        self.inner.available(&self.real_path(path))
+    }
+}
+
+struct EncryptedReader {
+    cipher: XChaCha20Poly1305,
+    inner: Box<dyn Read + Send>,
+    file_nonce: [u8; 16],
+    counter: u64,
+    /// Sealed bytes read ahead, to tell whether a segment is the last one.
+    ahead: Vec<u8>,
+    plain: Cursor<Vec<u8>>,
+    done: bool,
+}
+
+impl Read for EncryptedReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            let n = self.plain.read(buf)?;
+            if n > 0 || buf.is_empty() || self.done {
+                return Ok(n);
+            }
+
+            let mut chunk = [0u8; 8 * 1024];
+            while self.ahead.len() <= SEGMENT + TAG_LEN {
+                let n = self.inner.read(&mut chunk)?;
+                if n == 0 {
+                    break;
+                }
+                self.ahead.extend_from_slice(&chunk[..n]);
+            }
+
+            let last = self.ahead.len() <= SEGMENT + TAG_LEN;
+            let sealed: Vec<u8> = self.ahead.drain(..self.ahead.len().min(SEGMENT + TAG_LEN)).collect();
+            let plain = self
+                .cipher
+                .decrypt(&nonce(&self.file_nonce, self.counter, last), &sealed[..])
+                .map_err(|_| invalid("Encrypted file is corrupted or was tampered with"))?;
+            self.counter += 1;
+            self.done = last;
+            self.plain = Cursor::new(plain);
+        }
+    }
+}
+
+/// Seals full segments as they fill up; the last one is sealed on `flush`, or when
+/// dropped. Nothing can be written after a flush.
+struct EncryptedWriter {
+    cipher: XChaCha20Poly1305,
+    inner: Box<dyn Write + Send>,
+    file_nonce: [u8; 16],
+    counter: u64,
+    buffer: Vec<u8>,
+    finished: bool,
+}
+
+impl EncryptedWriter {
+    fn seal(&mut self, len: usize, last: bool) -> Result<()> {
+        let plain: Vec<u8> = self.buffer.drain(..len).collect();
+        let sealed = self
+            .cipher
+            .encrypt(&nonce(&self.file_nonce, self.counter, last), &plain[..])
+            .map_err(|_| Error::other("Encryption failed"))?;
+        self.counter += 1;
+        self.inner.write_all(&sealed)
+    }
+}
+
+impl Write for EncryptedWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.finished {
+            return Err(Error::other("Encrypted file already finished"));
+        }
+        self.buffer.extend_from_slice(buf);
+        // A full segment is only sealed once more data follows, as the last one must be
+        // flagged as such.
+        while self.buffer.len() > SEGMENT {
+            self.seal(SEGMENT, false)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if !self.finished {
+            self.finished = true;
+            self.seal(self.buffer.len(), true)?;
+        }
+        self.inner.flush()
+    }
+}
+
+impl Drop for EncryptedWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Err(e) = self.flush() {
+                println!("[!] Couldn't finish encrypted file: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{write_all, MemoryStorage};
+
+    const KEY: [u8; 32] = [7; 32];
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    fn read_all(storage: &dyn Storage, path: &Path) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        storage.read(path)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Applies `change` to the stored bytes of `path`.
+    fn tamper(inner: &MemoryStorage, path: &Path, change: impl FnOnce(&mut Vec<u8>)) {
+        let mut raw = read_all(inner, path).unwrap();
+        change(&mut raw);
+        write_all(inner, path, &raw).unwrap();
+    }
+
+    #[test]
+    fn round_trip() {
+        let inner = Arc::new(MemoryStorage::new());
+        let storage = EncryptedStorage::new(inner.clone(), &KEY, false);
+        for len in [0, 1, SEGMENT, 2 * SEGMENT, 2 * SEGMENT + 100] {
+            let path = PathBuf::from(format!("/{}.bin", len));
+            write_all(&storage, &path, &content(len)).unwrap();
+            assert_eq!(read_all(&storage, &path).unwrap(), content(len));
+            assert_eq!(storage.stat(&path).unwrap().len, len as u64);
+            assert!(read_all(&*inner, &path).unwrap().starts_with(MAGIC));
+        }
+    }
+
+    #[test]
+    fn encrypted_names_round_trip() {
+        let inner = Arc::new(MemoryStorage::new());
+        let storage = EncryptedStorage::new(inner.clone(), &KEY, true);
+        storage.mkdir(Path::new("/photos")).unwrap();
+        write_all(&storage, Path::new("/photos/cat.jpg"), b"meow").unwrap();
+
+        let names: Vec<String> = storage.list(Path::new("/photos")).unwrap().into_iter().map(|entry| entry.name).collect();
+        assert_eq!(names, ["cat.jpg"]);
+        assert!(inner.stat(Path::new("/photos")).is_err());
+        assert_eq!(read_all(&storage, Path::new("/photos/cat.jpg")).unwrap(), b"meow");
+    }
+
+    #[test]
+    fn detects_tampering() {
+        let inner = Arc::new(MemoryStorage::new());
+        let storage = EncryptedStorage::new(inner.clone(), &KEY, false);
+        let path = Path::new("/file");
+        write_all(&storage, path, &content(SEGMENT + 10)).unwrap();
+        tamper(&inner, path, |raw| raw[HEADER_LEN + 5] ^= 1);
+        assert_eq!(read_all(&storage, path).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        let other = EncryptedStorage::new(inner.clone(), &[8; 32], false);
+        write_all(&storage, path, b"secret").unwrap();
+        assert_eq!(read_all(&other, path).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn detects_truncation() {
+        let inner = Arc::new(MemoryStorage::new());
+        let storage = EncryptedStorage::new(inner.clone(), &KEY, false);
+        let path = Path::new("/file");
+        let segments = [HEADER_LEN, HEADER_LEN + SEGMENT + TAG_LEN, HEADER_LEN + 2 * (SEGMENT + TAG_LEN)];
+        for len in segments {
+            write_all(&storage, path, &content(2 * SEGMENT + 100)).unwrap();
+            tamper(&inner, path, |raw| raw.truncate(len));
+            assert_eq!(read_all(&storage, path).unwrap_err().kind(), ErrorKind::InvalidData, "{}", len);
+        }
+
+        write_all(&storage, path, b"short").unwrap();
+        tamper(&inner, path, |raw| raw.truncate(HEADER_LEN - 1));
+        assert_eq!(read_all(&storage, path).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,541 @@
+use std::collections::{HashSet, VecDeque};
+use std::io::{Cursor, Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+
+use sha2::{Digest, Sha256};
+
+use super::{create_dir_all, Entry, Metadata, Storage};
+use crate::config::META_DIR;
+
+const MIN_CHUNK: usize = 2 * 1024;
+const MAX_CHUNK: usize = 64 * 1024;
+/// Cut when the 13 high bits of the rolling hash are zero, for ~8 KiB chunks on average.
+const BOUNDARY_MASK: u64 = ((1 << 13) - 1) << 51;
+
+const MANIFEST_MAGIC: &[u8] = b"ventus-manifest 1\n";
+
+/// Numbers the partial files of chunks being stored, so concurrent writers of the same
+/// chunk never share one.
+static PARTIALS: AtomicU64 = AtomicU64::new(0);
+
+/// Random values for the gear rolling hash, generated with splitmix64 so chunk
+/// boundaries never change between builds.
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Deduplicating layer over another storage.
+///
+/// File contents are cut into content-defined chunks stored once, by SHA-256, in
+/// `/.ventus/chunks/<ab>/<hash>`. The file itself only holds a manifest listing its
+/// chunks, so renames, trash and versions work unchanged. Files that aren't manifests
+/// (written before deduplication was enabled) are passed through as they are.
+///
+/// Chunks are never removed when a file goes away; `collect_garbage` sweeps the ones
+/// no manifest references anymore.
+pub struct DedupStorage {
+    inner: Arc<dyn Storage>,
+    writers: Arc<Mutex<Writers>>,
+    /// Held for reading by renames and deletes, and for writing by garbage collection,
+    /// so no manifest moves to a directory it has already walked.
+    moves: RwLock<()>,
+}
+
+#[derive(Default)]
+struct Writers {
+    active: usize,
+    collecting: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct DedupStats {
+    /// Files and the bytes they'd take without deduplication.
+    pub files: u64,
+    pub logical: u64,
+    /// Distinct chunks referenced by the files, and the bytes they take.
+    pub chunks: u64,
+    pub stored: u64,
+    /// Chunks no file references anymore, waiting for garbage collection.
+    pub orphans: u64,
+    pub orphan_bytes: u64,
+}
+
+struct Manifest {
+    size: u64,
+    chunks: Vec<(String, u64)>,
+}
+
+fn chunks_dir() -> PathBuf {
+    Path::new("/").join(META_DIR).join("chunks")
+}
+
+fn chunk_path(hash: &str) -> PathBuf {
+    chunks_dir().join(&hash[..2]).join(hash)
+}
+
+fn sha256(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn corrupted(path: &Path) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Corrupted manifest {}", path.display()))
+}
+
+impl DedupStorage {
+    pub fn new(inner: Arc<dyn Storage>) -> DedupStorage {
+        DedupStorage {
+            inner,
+            writers: Arc::new(Mutex::new(Writers::default())),
+            moves: RwLock::new(()),
+        }
+    }
+
+    /// The manifest stored at `path`, or `None` for a plain file.
+    fn manifest(&self, path: &Path) -> Result<Option<Manifest>> {
+        let mut file = self.inner.read(path)?;
+        let mut magic = Vec::new();
+        (&mut file).take(MANIFEST_MAGIC.len() as u64).read_to_end(&mut magic)?;
+        if magic != MANIFEST_MAGIC {
+            return Ok(None);
+        }
+
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        let mut lines = content.lines();
+        let size = lines
+            .next()
+            .and_then(|line| line.strip_prefix("size "))
+            .and_then(|size| size.parse().ok())
+            .ok_or_else(|| corrupted(path))?;
+        let mut chunks = Vec::new();
+        for line in lines {
+            let (hash, len) = line.split_once(' ').ok_or_else(|| corrupted(path))?;
+            if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(corrupted(path));
+            }
+            chunks.push((hash.to_string(), len.parse().map_err(|_| corrupted(path))?));
+        }
+        Ok(Some(Manifest { size, chunks }))
+    }
+
+    fn metadata(&self, path: &Path, metadata: Metadata) -> Result<Metadata> {
+        if metadata.is_dir {
+            return Ok(metadata);
+        }
+        match self.manifest(path)? {
+            Some(manifest) => Ok(Metadata { len: manifest.size, ..metadata }),
+            None => Ok(metadata),
+        }
+    }
+
+    /// Calls `visit` with every manifest in the storage, trash and versions included.
+    fn walk(&self, dir: &Path, visit: &mut dyn FnMut(Manifest)) -> Result<()> {
+        for entry in self.inner.list(dir)? {
+            let path = dir.join(&entry.name);
+            if path == chunks_dir() {
+                continue;
+            }
+            if entry.metadata.is_dir {
+                self.walk(&path, visit)?;
+            } else if let Ok(Some(manifest)) = self.manifest(&path) {
+                visit(manifest);
+            }
+        }
+        Ok(())
+    }
+
+    /// Every stored chunk, with its size.
+    fn stored_chunks(&self) -> Result<Vec<(String, u64)>> {
+        let mut chunks = Vec::new();
+        let prefixes = match self.inner.list(&chunks_dir()) {
+            Ok(prefixes) => prefixes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(chunks),
+            Err(e) => return Err(e),
+        };
+        for prefix in prefixes.iter().filter(|prefix| prefix.metadata.is_dir) {
+            for chunk in self.inner.list(&chunks_dir().join(&prefix.name))? {
+                if chunk.name.len() == 64 {
+                    chunks.push((chunk.name, chunk.metadata.len));
+                }
+            }
+        }
+        Ok(chunks)
+    }
+
+    pub fn stats(&self) -> Result<DedupStats> {
+        let mut stats = DedupStats::default();
+        let mut referenced = HashSet::new();
+        self.walk(Path::new("/"), &mut |manifest| {
+            stats.files += 1;
+            stats.logical += manifest.size;
+            referenced.extend(manifest.chunks.into_iter().map(|(hash, _)| hash));
+        })?;
+
+        for (hash, len) in self.stored_chunks()? {
+            if referenced.contains(&hash) {
+                stats.chunks += 1;
+                stats.stored += len;
+            } else {
+                stats.orphans += 1;
+                stats.orphan_bytes += len;
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Deletes the chunks no manifest references. Refused while uploads are running,
+    /// as their chunks aren't referenced yet, and renames and deletes wait until it's done.
+    /// Returns the number of chunks and bytes freed.
+    pub fn collect_garbage(&self) -> Result<(u64, u64)> {
+        {
+            let mut writers = self.writers.lock().unwrap_or_else(PoisonError::into_inner);
+            if writers.active > 0 || writers.collecting {
+                return Err(Error::new(ErrorKind::WouldBlock, "Uploads in progress"));
+            }
+            writers.collecting = true;
+        }
+
+        let moves = self.moves.write().unwrap_or_else(PoisonError::into_inner);
+        let result = (|| {
+            let mut referenced = HashSet::new();
+            self.walk(Path::new("/"), &mut |manifest| {
+                referenced.extend(manifest.chunks.into_iter().map(|(hash, _)| hash));
+            })?;
+
+            let (mut count, mut bytes) = (0, 0);
+            for (hash, len) in self.stored_chunks()? {
+                if !referenced.contains(&hash) {
+                    self.inner.delete(&chunk_path(&hash))?;
+                    count += 1;
+                    bytes += len;
+                }
+            }
+            Ok((count, bytes))
+        })();
+        drop(moves);
+
+        self.writers.lock().unwrap_or_else(PoisonError::into_inner).collecting = false;
+        result
+    }
+}
+
+impl Storage for DedupStorage {
+    fn list(&self, path: &Path) -> Result<Vec<Entry>> {
+        let mut entries = self.inner.list(path)?;
+        for entry in &mut entries {
+            // An unreadable manifest is listed as stored, so it can still be deleted.
+            match self.metadata(&path.join(&entry.name), entry.metadata.clone()) {
+                Ok(metadata) => entry.metadata = metadata,
+                Err(e) => println!("[!] Couldn't read {}: {}", path.join(&entry.name).display(), e),
+            }
+        }
+        Ok(entries)
+    }
+
+    fn stat(&self, path: &Path) -> Result<Metadata> {
+        self.metadata(path, self.inner.stat(path)?)
+    }
+
+    fn read(&self, path: &Path) -> Result<Box<dyn Read + Send>> {
+        match self.manifest(path)? {
+            Some(manifest) => Ok(Box::new(DedupReader {
+                inner: Arc::clone(&self.inner),
+                chunks: manifest.chunks.into(),
+                current: Cursor::new(Vec::new()),
+            })),
+            None => self.inner.read(path),
+        }
+    }
+
+    fn write(&self, path: &Path) -> Result<Box<dyn Write + Send>> {
+        {
+            let mut writers = self.writers.lock().unwrap_or_else(PoisonError::into_inner);
+            if writers.collecting {
+                return Err(Error::new(ErrorKind::WouldBlock, "Garbage collection in progress"));
+            }
+            writers.active += 1;
+        }
+
+        let mut writer = DedupWriter {
+            inner: Arc::clone(&self.inner),
+            writers: Arc::clone(&self.writers),
+            path: path.to_path_buf(),
+            buffer: Vec::new(),
+            scanned: 0,
+            hash: 0,
+            chunks: Vec::new(),
+            size: 0,
+            finished: false,
+        };
+        // Create or truncate the file right away, like any other backend.
+        writer.finish()?;
+        Ok(Box::new(writer))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let _moves = self.moves.read().unwrap_or_else(PoisonError::into_inner);
+        self.inner.rename(from, to)
+    }
+
+    fn delete(&self, path: &Path) -> Result<()> {
+        let _moves = self.moves.read().unwrap_or_else(PoisonError::into_inner);
+        self.inner.delete(path)
+    }
+
+    fn mkdir(&self, path: &Path) -> Result<()> {
+        self.inner.mkdir(path)
+    }
+
+    fn available(&self, path: &Path) -> Result<u64> {
+        self.inner.available(path)
+    }
+
+    fn dedup(&self) -> Option<&DedupStorage> {
+        Some(self)
+    }
+}
+
+struct DedupReader {
+    inner: Arc<dyn Storage>,
+    chunks: VecDeque<(String, u64)>,
+    current: Cursor<Vec<u8>>,
+}
+
+impl Read for DedupReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            let n = self.current.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            let Some((hash, len)) = self.chunks.pop_front() else {
+                return Ok(0);
+            };
+
+            let mut data = Vec::with_capacity(len as usize);
+            self.inner.read(&chunk_path(&hash))?.read_to_end(&mut data)?;
+            if data.len() as u64 != len || sha256(&data) != hash {
+                return Err(Error::new(ErrorKind::InvalidData, format!("Corrupted chunk {}", hash)));
+            }
+            self.current = Cursor::new(data);
+        }
+    }
+}
+
+/// Cuts the written bytes into chunks with a gear rolling hash, stores the new ones and
+/// writes the manifest on `flush`, or when dropped.
+struct DedupWriter {
+    inner: Arc<dyn Storage>,
+    writers: Arc<Mutex<Writers>>,
+    path: PathBuf,
+    buffer: Vec<u8>,
+    /// Bytes of `buffer` already fed to the rolling `hash`.
+    scanned: usize,
+    hash: u64,
+    chunks: Vec<(String, u64)>,
+    size: u64,
+    finished: bool,
+}
+
+impl DedupWriter {
+    fn store_chunk(&mut self, len: usize) -> Result<()> {
+        let data: Vec<u8> = self.buffer.drain(..len).collect();
+        let hash = sha256(&data);
+        let path = chunk_path(&hash);
+
+        if self.inner.stat(&path).is_err() {
+            if let Some(parent) = path.parent() {
+                create_dir_all(&*self.inner, parent)?;
+            }
+            // Written aside first, so a crash never leaves a truncated chunk under its hash.
+            let partial = path.with_extension(format!("part-{}", PARTIALS.fetch_add(1, Ordering::Relaxed)));
+            let mut file = self.inner.write(&partial)?;
+            file.write_all(&data)?;
+            file.flush()?;
+            drop(file);
+            if let Err(e) = self.inner.rename(&partial, &path) {
+                let _ = self.inner.delete(&partial);
+                // Another writer stored the same chunk meanwhile.
+                if self.inner.stat(&path).is_err() {
+                    return Err(e);
+                }
+            }
+        }
+
+        self.chunks.push((hash, data.len() as u64));
+        self.size += data.len() as u64;
+        self.scanned = 0;
+        self.hash = 0;
+        Ok(())
+    }
+
+    /// Stores the pending bytes as a last chunk and writes the manifest.
+    fn finish(&mut self) -> Result<()> {
+        if !self.buffer.is_empty() {
+            self.store_chunk(self.buffer.len())?;
+        }
+
+        let mut manifest = String::from_utf8_lossy(MANIFEST_MAGIC).to_string();
+        manifest.push_str(&format!("size {}\n", self.size));
+        for (hash, len) in &self.chunks {
+            manifest.push_str(&format!("{} {}\n", hash, len));
+        }
+        let mut file = self.inner.write(&self.path)?;
+        file.write_all(manifest.as_bytes())?;
+        file.flush()?;
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl Write for DedupWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.finished = false;
+        self.buffer.extend_from_slice(buf);
+        while self.scanned < self.buffer.len() {
+            self.hash = (self.hash << 1).wrapping_add(GEAR[self.buffer[self.scanned] as usize]);
+            self.scanned += 1;
+            if (self.scanned >= MIN_CHUNK && self.hash & BOUNDARY_MASK == 0) || self.scanned >= MAX_CHUNK {
+                self.store_chunk(self.scanned)?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl Drop for DedupWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Err(e) = self.finish() {
+                println!("[!] Couldn't write manifest of {}: {}", self.path.display(), e);
+            }
+        }
+        self.writers.lock().unwrap_or_else(PoisonError::into_inner).active -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{write_all, MemoryStorage};
+
+    /// Bytes from a xorshift generator, so chunk boundaries fall where the content says.
+    fn content(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn read_all(storage: &dyn Storage, path: &Path) -> Vec<u8> {
+        let mut data = Vec::new();
+        storage.read(path).unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn chunks_and_manifest_round_trip() {
+        let storage = DedupStorage::new(Arc::new(MemoryStorage::new()));
+        let path = Path::new("/file");
+        let data = content(300_000, 1);
+        write_all(&storage, path, &data).unwrap();
+
+        assert_eq!(read_all(&storage, path), data);
+        assert_eq!(storage.stat(path).unwrap().len, data.len() as u64);
+        let manifest = storage.manifest(path).unwrap().unwrap();
+        assert_eq!(manifest.size, data.len() as u64);
+        assert_eq!(manifest.chunks.iter().map(|(_, len)| len).sum::<u64>(), data.len() as u64);
+        let (last, rest) = manifest.chunks.split_last().unwrap();
+        assert!(rest.iter().all(|(_, len)| (MIN_CHUNK as u64..=MAX_CHUNK as u64).contains(len)));
+        assert!(last.1 <= MAX_CHUNK as u64);
+        assert!(manifest.chunks.len() > 10, "{} chunks", manifest.chunks.len());
+    }
+
+    #[test]
+    fn identical_content_is_stored_once() {
+        let storage = DedupStorage::new(Arc::new(MemoryStorage::new()));
+        let data = content(100_000, 2);
+        write_all(&storage, Path::new("/a"), &data).unwrap();
+        write_all(&storage, Path::new("/b"), &data).unwrap();
+        // An edit in the middle only changes the chunks around it.
+        let mut edited = data.clone();
+        edited[50_000] ^= 1;
+        write_all(&storage, Path::new("/c"), &edited).unwrap();
+
+        let stats = storage.stats().unwrap();
+        assert_eq!(stats.files, 3);
+        assert_eq!(stats.logical, 300_000);
+        assert!(stats.stored < 100_000 + 2 * MAX_CHUNK as u64, "{} bytes stored", stats.stored);
+        assert_eq!(read_all(&storage, Path::new("/c")), edited);
+    }
+
+    #[test]
+    fn concurrent_writers_of_the_same_chunks() {
+        let storage = Arc::new(DedupStorage::new(Arc::new(MemoryStorage::new())));
+        let data = Arc::new(content(200_000, 5));
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let (storage, data) = (Arc::clone(&storage), Arc::clone(&data));
+                std::thread::spawn(move || write_all(&*storage, Path::new(&format!("/{}", i)), &data))
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap().unwrap();
+        }
+        for i in 0..8 {
+            assert_eq!(read_all(&*storage, Path::new(&format!("/{}", i))), *data);
+        }
+        assert_eq!(storage.stats().unwrap().stored, 200_000);
+    }
+
+    #[test]
+    fn garbage_collection_keeps_referenced_chunks() {
+        let storage = DedupStorage::new(Arc::new(MemoryStorage::new()));
+        let (kept, dropped) = (content(50_000, 3), content(50_000, 4));
+        write_all(&storage, Path::new("/kept"), &kept).unwrap();
+        write_all(&storage, Path::new("/dropped"), &dropped).unwrap();
+        storage.delete(Path::new("/dropped")).unwrap();
+
+        let (count, bytes) = storage.collect_garbage().unwrap();
+        assert!(count > 0);
+        assert_eq!(bytes, 50_000);
+        assert_eq!(storage.stats().unwrap().orphans, 0);
+        assert_eq!(read_all(&storage, Path::new("/kept")), kept);
+    }
+
+    #[test]
+    fn plain_files_and_bad_manifests() {
+        let inner = Arc::new(MemoryStorage::new());
+        let storage = DedupStorage::new(inner.clone());
+        write_all(&*inner, Path::new("/plain"), b"written before dedup").unwrap();
+        assert_eq!(read_all(&storage, Path::new("/plain")), b"written before dedup");
+
+        let mut bad = MANIFEST_MAGIC.to_vec();
+        bad.extend_from_slice(b"garbage\n");
+        write_all(&*inner, Path::new("/bad"), &bad).unwrap();
+        assert_eq!(storage.read(Path::new("/bad")).err().unwrap().kind(), ErrorKind::InvalidData);
+        assert_eq!(storage.list(Path::new("/")).unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,99 @@
+use std::fs::{self, create_dir, read_dir, remove_dir_all, remove_file, File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::{Entry, Metadata, Storage};
+use crate::jail::{Jail, SymlinkPolicy};
+
+/// Files in a directory on the local disk, reached through a `Jail`.
+pub struct LocalStorage {
+    jail: Jail,
+}
+
+impl LocalStorage {
+    pub fn new(root: &Path, symlinks: SymlinkPolicy) -> LocalStorage {
+        LocalStorage {
+            jail: Jail::new(root, symlinks),
+        }
+    }
+}
+
+fn metadata(metadata: fs::Metadata) -> Metadata {
+    Metadata {
+        is_dir: metadata.is_dir(),
+        len: metadata.len(),
+        modified: metadata.modified().ok(),
+    }
+}
+
+impl Storage for LocalStorage {
+    fn list(&self, path: &Path) -> Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        for entry in read_dir(self.jail.resolve(path)?)?.flatten() {
+            if let Ok(entry_metadata) = entry.metadata() {
+                entries.push(Entry {
+                    name: entry.file_name().to_string_lossy().to_string(),
+                    metadata: metadata(entry_metadata),
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    fn stat(&self, path: &Path) -> Result<Metadata> {
+        fs::metadata(self.jail.resolve(path)?).map(metadata)
+    }
+
+    fn read(&self, path: &Path) -> Result<Box<dyn Read + Send>> {
+        Ok(Box::new(File::open(self.jail.resolve(path)?)?))
+    }
+
+    fn write(&self, path: &Path) -> Result<Box<dyn Write + Send>> {
+        let file = OpenOptions::new().write(true).create(true).truncate(true).open(self.jail.resolve(path)?)?;
+        Ok(Box::new(file))
+    }
+
+    fn write_at(&self, path: &Path, offset: u64) -> Result<Box<dyn Write + Send>> {
+        let mut file = OpenOptions::new().write(true).create(true).truncate(false).open(self.jail.resolve(path)?)?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(Box::new(file))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        fs::rename(self.jail.resolve_entry(from)?, self.jail.resolve_entry(to)?)
+    }
+
+    fn delete(&self, path: &Path) -> Result<()> {
+        let real = self.jail.resolve_entry(path)?;
+        if fs::symlink_metadata(&real)?.is_dir() {
+            remove_dir_all(real)
+        } else {
+            remove_file(real)
+        }
+    }
+
+    fn mkdir(&self, path: &Path) -> Result<()> {
+        create_dir(self.jail.resolve(path)?)
+    }
+
+    fn local_file(&self, path: &Path) -> Option<File> {
+        File::open(self.jail.resolve(path).ok()?).ok()
+    }
+
+    #[cfg(target_os = "linux")]
+    fn available(&self, path: &Path) -> Result<u64> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let real = self.jail.resolve(path)?;
+        let real = CString::new(real.as_os_str().as_bytes()).map_err(|_| Error::new(ErrorKind::InvalidInput, "Invalid path"))?;
+        // SAFETY: statvfs only writes into `stat`, and `real` is a valid C string.
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(real.as_ptr(), &mut stat) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        // f_bavail leaves out the blocks reserved for root, and reflects project quotas
+        // on filesystems that report them this way.
+        Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::BTreeMap;
+use std::io::{Cursor, Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::SystemTime;
+
+use super::{Entry, Metadata, Storage};
+
+/// A directory or a file, with its modification time.
+enum Node {
+    Dir(SystemTime),
+    File(Vec<u8>, SystemTime),
+}
+
+impl Node {
+    fn metadata(&self) -> Metadata {
+        match self {
+            Node::Dir(modified) => Metadata { is_dir: true, len: 0, modified: Some(*modified) },
+            Node::File(data, modified) => Metadata { is_dir: false, len: data.len() as u64, modified: Some(*modified) },
+        }
+    }
+}
+
+type Nodes = Arc<Mutex<BTreeMap<PathBuf, Node>>>;
+
+/// Files kept in memory and lost on exit, for tests and throwaway servers.
+pub struct MemoryStorage {
+    nodes: Nodes,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(PathBuf::from("/"), Node::Dir(SystemTime::now()));
+        MemoryStorage {
+            nodes: Arc::new(Mutex::new(nodes)),
+        }
+    }
+}
+
+/// Locks the nodes. Nothing panics while holding the lock, but should it happen, the
+/// nodes are still consistent, so the other sessions carry on.
+fn lock(nodes: &Nodes) -> MutexGuard<'_, BTreeMap<PathBuf, Node>> {
+    nodes.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn not_found() -> Error {
+    Error::new(ErrorKind::NotFound, "No such file or directory")
+}
+
+/// Checks that the parent of `path` is an existing directory.
+fn check_parent(nodes: &BTreeMap<PathBuf, Node>, path: &Path) -> Result<()> {
+    match path.parent().and_then(|parent| nodes.get(parent)) {
+        Some(Node::Dir(_)) => Ok(()),
+        Some(Node::File(..)) => Err(Error::new(ErrorKind::NotADirectory, "Not a directory")),
+        None => Err(not_found()),
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn list(&self, path: &Path) -> Result<Vec<Entry>> {
+        let nodes = lock(&self.nodes);
+        match nodes.get(path) {
+            Some(Node::Dir(_)) => {}
+            Some(Node::File(..)) => return Err(Error::new(ErrorKind::NotADirectory, "Not a directory")),
+            None => return Err(not_found()),
+        }
+
+        Ok(nodes
+            .iter()
+            .filter(|(child, _)| child.parent() == Some(path))
+            .map(|(child, node)| Entry {
+                name: child.file_name().unwrap_or_default().to_string_lossy().to_string(),
+                metadata: node.metadata(),
+            })
+            .collect())
+    }
+
+    fn stat(&self, path: &Path) -> Result<Metadata> {
+        lock(&self.nodes).get(path).map(Node::metadata).ok_or_else(not_found)
+    }
+
+    fn read(&self, path: &Path) -> Result<Box<dyn Read + Send>> {
+        match lock(&self.nodes).get(path) {
+            Some(Node::File(data, _)) => Ok(Box::new(Cursor::new(data.clone()))),
+            Some(Node::Dir(_)) => Err(Error::new(ErrorKind::IsADirectory, "Is a directory")),
+            None => Err(not_found()),
+        }
+    }
+
+    fn write(&self, path: &Path) -> Result<Box<dyn Write + Send>> {
+        let mut nodes = lock(&self.nodes);
+        check_parent(&nodes, path)?;
+        if let Some(Node::Dir(_)) = nodes.get(path) {
+            return Err(Error::new(ErrorKind::IsADirectory, "Is a directory"));
+        }
+        nodes.insert(path.to_path_buf(), Node::File(Vec::new(), SystemTime::now()));
+
+        Ok(Box::new(MemoryWriter {
+            nodes: Arc::clone(&self.nodes),
+            path: path.to_path_buf(),
+            position: 0,
+        }))
+    }
+
+    /// Only up to the end of the file: memory isn't sparse, so a gap would be allocated
+    /// in full.
+    fn write_at(&self, path: &Path, offset: u64) -> Result<Box<dyn Write + Send>> {
+        let mut nodes = lock(&self.nodes);
+        check_parent(&nodes, path)?;
+        let len = match nodes.get(path) {
+            Some(Node::Dir(_)) => return Err(Error::new(ErrorKind::IsADirectory, "Is a directory")),
+            Some(Node::File(data, _)) => data.len() as u64,
+            None => 0,
+        };
+        if offset > len {
+            return Err(Error::new(ErrorKind::InvalidInput, "Offset is past the end of the file"));
+        }
+        nodes.entry(path.to_path_buf()).or_insert_with(|| Node::File(Vec::new(), SystemTime::now()));
+
+        Ok(Box::new(MemoryWriter {
+            nodes: Arc::clone(&self.nodes),
+            path: path.to_path_buf(),
+            position: offset as usize,
+        }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut nodes = lock(&self.nodes);
+        if !nodes.contains_key(from) {
+            return Err(not_found());
+        }
+        check_parent(&nodes, to)?;
+        if to.starts_with(from) {
+            return Err(Error::new(ErrorKind::InvalidInput, "Can't move a directory into itself"));
+        }
+        if let Some(Node::Dir(_)) = nodes.get(to) {
+            return Err(Error::new(ErrorKind::AlreadyExists, "Target is a directory"));
+        }
+
+        let moved: Vec<PathBuf> = nodes.keys().filter(|path| path.starts_with(from)).cloned().collect();
+        for path in moved {
+            if let Some(node) = nodes.remove(&path) {
+                let relative = path.strip_prefix(from).unwrap_or(Path::new(""));
+                nodes.insert(to.join(relative), node);
+            }
+        }
+        Ok(())
+    }
+
+    fn delete(&self, path: &Path) -> Result<()> {
+        let mut nodes = lock(&self.nodes);
+        if path == Path::new("/") || !nodes.contains_key(path) {
+            return Err(not_found());
+        }
+        nodes.retain(|node, _| !node.starts_with(path));
+        Ok(())
+    }
+
+    fn mkdir(&self, path: &Path) -> Result<()> {
+        let mut nodes = lock(&self.nodes);
+        check_parent(&nodes, path)?;
+        if nodes.contains_key(path) {
+            return Err(Error::new(ErrorKind::AlreadyExists, "File exists"));
+        }
+        nodes.insert(path.to_path_buf(), Node::Dir(SystemTime::now()));
+        Ok(())
+    }
+}
+
+/// Writes straight into the stored file, so readers see data as it arrives.
+struct MemoryWriter {
+    nodes: Nodes,
+    path: PathBuf,
+    /// Where the next write goes. It's past the end only if the file was truncated
+    /// meanwhile, and the gap is then padded with zeros.
+    position: usize,
+}
+
+impl Write for MemoryWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match lock(&self.nodes).get_mut(&self.path) {
+            Some(Node::File(data, modified)) => {
+                let end = self
+                    .position
+                    .checked_add(buf.len())
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "File too large"))?;
+                if data.len() < end {
+                    data.resize(end, 0);
+                }
+                data[self.position..end].copy_from_slice(buf);
+                self.position = end;
+                *modified = SystemTime::now();
+                Ok(buf.len())
+            }
+            _ => Err(not_found()),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+use std::env;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use sha2::{Digest, Sha256};
+
+use crate::config::{Config, StorageBackend, META_DIR};
+
+mod crypt;
+mod dedup;
+mod local;
+mod memory;
+mod shares;
+
+pub use crypt::EncryptedStorage;
+pub use dedup::DedupStorage;
+pub use local::LocalStorage;
+pub use memory::MemoryStorage;
+pub use shares::ShareStorage;
+
+/// Where the server keeps files.
+///
+/// Paths are virtual: absolute, normalized (no `.` or `..`) and rooted at the storage
+/// root, e.g. `/docs/a.txt`. Access control is the caller's business.
+pub trait Storage: Send + Sync {
+    fn list(&self, path: &Path) -> Result<Vec<Entry>>;
+    fn stat(&self, path: &Path) -> Result<Metadata>;
+    fn read(&self, path: &Path) -> Result<Box<dyn Read + Send>>;
+    /// Creates or truncates a file.
+    fn write(&self, path: &Path) -> Result<Box<dyn Write + Send>>;
+    /// Writes into a file from `offset` on, keeping the rest of it, for uploads in
+    /// parallel segments. Storages that transform content as a whole can't.
+    fn write_at(&self, _path: &Path, _offset: u64) -> Result<Box<dyn Write + Send>> {
+        Err(Error::new(ErrorKind::Unsupported, "Writing at an offset is not supported"))
+    }
+    fn rename(&self, from: &Path, to: &Path) -> Result<()>;
+    /// Deletes a file, or a directory with everything in it.
+    fn delete(&self, path: &Path) -> Result<()>;
+    fn mkdir(&self, path: &Path) -> Result<()>;
+
+    /// The file on the local disk behind `path`, for zero-copy sends. Only storages
+    /// keeping files as they are have one.
+    fn local_file(&self, _path: &Path) -> Option<File> {
+        None
+    }
+
+    /// Bytes that can still be stored under the directory `path`, as the backing
+    /// filesystem reports them to unprivileged users, so within its quotas.
+    fn available(&self, _path: &Path) -> Result<u64> {
+        Err(Error::new(ErrorKind::Unsupported, "Available space is unknown"))
+    }
+
+    /// The deduplicating layer, when this storage is one.
+    fn dedup(&self) -> Option<&DedupStorage> {
+        None
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    pub is_dir: bool,
+    pub len: u64,
+    /// Last modification, when the backend knows it.
+    pub modified: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub name: String,
+    pub metadata: Metadata,
+}
+
+/// Opens the storage backend selected in the config.
+pub fn open(config: &Config) -> Arc<dyn Storage> {
+    let storage: Arc<dyn Storage> = match config.storage.backend {
+        StorageBackend::Local => {
+            let root = env::current_dir().unwrap_or_else(|e| panic!("Couldn't read the current directory: {}", e));
+            Arc::new(LocalStorage::new(&root, config.jail.symlinks))
+        }
+        StorageBackend::Memory => {
+            println!("[*] Using in-memory storage, files are lost when the server stops");
+            Arc::new(MemoryStorage::new())
+        }
+    };
+
+    // The base storage keeps the metadata directory, next to the shares in the root.
+    let storage: Arc<dyn Storage> = if config.shares.is_empty() {
+        storage
+    } else {
+        // Encrypted names would hide the share names the root is made of.
+        if config.storage.encryption.as_ref().is_some_and(|encryption| encryption.encrypt_names) {
+            panic!("Shares can't be used with encrypted names");
+        }
+        let shares = ShareStorage::new(storage, &config.shares, config.jail.symlinks)
+            .unwrap_or_else(|e| panic!("Couldn't set up shares: {}", e));
+        let names: Vec<&str> = config.shares.iter().map(|share| share.name.as_str()).collect();
+        println!("[*] Sharing {}", names.join(", "));
+        Arc::new(shares)
+    };
+
+    // Below deduplication, which needs to see the plaintext to find identical content.
+    let storage = match &config.storage.encryption {
+        Some(encryption) => {
+            let key = crypt::load_key(&encryption.key_file)
+                .unwrap_or_else(|e| panic!("Couldn't read key file {}: {}", encryption.key_file.display(), e));
+            println!("[*] Encrypting stored files{}", if encryption.encrypt_names { " and names" } else { "" });
+            Arc::new(EncryptedStorage::new(storage, &key, encryption.encrypt_names))
+        }
+        None => storage,
+    };
+
+    if config.storage.dedup {
+        println!("[*] Deduplicating file contents");
+        return Arc::new(DedupStorage::new(storage));
+    }
+    storage
+}
+
+/// Creates `path` and all its missing parents.
+pub fn create_dir_all(storage: &dyn Storage, path: &Path) -> Result<()> {
+    match storage.stat(path) {
+        Ok(metadata) if metadata.is_dir => return Ok(()),
+        Ok(_) => return Err(Error::new(ErrorKind::AlreadyExists, "Not a directory")),
+        Err(_) => {}
+    }
+    if let Some(parent) = path.parent() {
+        create_dir_all(storage, parent)?;
+    }
+    match storage.mkdir(path) {
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(()),
+        result => result,
+    }
+}
+
+pub fn read_to_string(storage: &dyn Storage, path: &Path) -> Result<String> {
+    let mut content = String::new();
+    storage.read(path)?.read_to_string(&mut content)?;
+    Ok(content)
+}
+
+pub fn write_all(storage: &dyn Storage, path: &Path, content: &[u8]) -> Result<()> {
+    let mut file = storage.write(path)?;
+    file.write_all(content)?;
+    file.flush()
+}
+
+pub fn copy(storage: &dyn Storage, from: &Path, to: &Path) -> Result<u64> {
+    let mut reader = storage.read(from)?;
+    let mut writer = storage.write(to)?;
+    let copied = std::io::copy(&mut reader, &mut writer)?;
+    writer.flush()?;
+    Ok(copied)
+}
+
+/// Marks the files written next to their target until they replace it, for uploads in
+/// progress and version restores.
+const STAGED_MARKER: &str = ".ventus-staged-";
+
+/// Where content for `path` is staged until it's complete, `tag` telling writers apart.
+/// It's next to `path`, so it's put in place with a rename within the same directory.
+pub fn staged_path(path: &Path, tag: &str) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}{}{}", name, STAGED_MARKER, tag))
+}
+
+/// Whether `name` is a file from `staged_path`, which listings and walks leave out.
+pub fn is_staged(name: &str) -> bool {
+    name.starts_with('.') && name.contains(STAGED_MARKER)
+}
+
+/// Visits everything under the directory `root`, depth first and sorted by name, with
+/// paths relative to `root`. The top-level metadata directory and staged files are skipped.
+pub fn walk(storage: &dyn Storage, root: &Path, visit: &mut dyn FnMut(&Path, &Entry) -> Result<()>) -> Result<()> {
+    walk_from(storage, root, Path::new(""), visit)
+}
+
+fn walk_from(storage: &dyn Storage, root: &Path, relative: &Path, visit: &mut dyn FnMut(&Path, &Entry) -> Result<()>) -> Result<()> {
+    let dir = if relative.as_os_str().is_empty() { root.to_path_buf() } else { root.join(relative) };
+    let mut entries = storage.list(&dir)?;
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    for entry in entries {
+        if (dir == Path::new("/") && entry.name == META_DIR) || is_staged(&entry.name) {
+            continue;
+        }
+        let path = relative.join(&entry.name);
+        visit(&path, &entry)?;
+        if entry.metadata.is_dir {
+            walk_from(storage, root, &path, visit)?;
+        }
+    }
+    Ok(())
+}
+
+/// Hex SHA-256 of a file's content.
+pub fn sha256(storage: &dyn Storage, path: &Path) -> Result<String> {
+    let mut file = storage.read(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
@@ -0,0 +1,195 @@
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use super::{Entry, LocalStorage, Metadata, Storage};
+use crate::config::{ShareConfig, META_DIR};
+use crate::jail::SymlinkPolicy;
+
+/// Named directories on disk, seen as the top-level directories of a virtual root.
+///
+/// The root itself only holds the shares. The metadata directory stays in the storage
+/// the shares are layered on, so trash and versions work the same for every share.
+pub struct ShareStorage {
+    meta: Arc<dyn Storage>,
+    shares: Vec<Share>,
+    created: SystemTime,
+}
+
+struct Share {
+    name: String,
+    storage: LocalStorage,
+    read_only: bool,
+}
+
+/// What a virtual path falls on.
+enum Target<'a> {
+    /// The virtual root.
+    Root,
+    /// A path inside a backend, with the path to use there.
+    Inner(&'a dyn Storage, PathBuf, Option<&'a Share>),
+}
+
+fn denied(message: &str) -> Error {
+    Error::new(ErrorKind::PermissionDenied, message)
+}
+
+impl ShareStorage {
+    /// Fails when a share has an invalid or duplicate name, or its path isn't a directory.
+    pub fn new(meta: Arc<dyn Storage>, config: &[ShareConfig], symlinks: SymlinkPolicy) -> Result<ShareStorage> {
+        let mut shares: Vec<Share> = Vec::new();
+        for share in config {
+            let mut components = Path::new(&share.name).components();
+            let valid = matches!(components.next(), Some(Component::Normal(_))) && components.next().is_none();
+            if !valid || share.name == META_DIR {
+                return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid share name \"{}\"", share.name)));
+            }
+            if shares.iter().any(|existing| existing.name == share.name) {
+                return Err(Error::new(ErrorKind::InvalidInput, format!("Duplicate share \"{}\"", share.name)));
+            }
+            if !share.path.is_dir() {
+                return Err(Error::new(ErrorKind::NotFound, format!("{} is not a directory", share.path.display())));
+            }
+            shares.push(Share {
+                name: share.name.clone(),
+                storage: LocalStorage::new(&share.path, symlinks),
+                read_only: share.read_only,
+            });
+        }
+        Ok(ShareStorage {
+            meta,
+            shares,
+            created: SystemTime::now(),
+        })
+    }
+
+    fn target(&self, path: &Path) -> Result<Target<'_>> {
+        let mut names = path.iter().skip(1);
+        let Some(top) = names.next() else {
+            return Ok(Target::Root);
+        };
+        if top == META_DIR {
+            return Ok(Target::Inner(&*self.meta, path.to_path_buf(), None));
+        }
+        let share = self
+            .shares
+            .iter()
+            .find(|share| top == share.name.as_str())
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "No such file or directory"))?;
+        let rest = Path::new("/").join(names.collect::<PathBuf>());
+        Ok(Target::Inner(&share.storage, rest, Some(share)))
+    }
+
+    /// Like `target`, for changing `path`: the root and the shares themselves stay as
+    /// configured, and read-only shares as they are.
+    fn writable(&self, path: &Path) -> Result<(&dyn Storage, PathBuf)> {
+        match self.target(path) {
+            Ok(Target::Inner(_, _, Some(share))) if share.read_only => Err(denied("Share is read-only")),
+            Ok(Target::Inner(_, rest, Some(_))) if rest == Path::new("/") => Err(denied("Shares can't be changed")),
+            Ok(Target::Inner(storage, rest, _)) => Ok((storage, rest)),
+            Ok(Target::Root) | Err(_) => Err(denied("Only shares can be in the root")),
+        }
+    }
+
+    fn root_metadata(&self) -> Metadata {
+        Metadata {
+            is_dir: true,
+            len: 0,
+            modified: Some(self.created),
+        }
+    }
+}
+
+/// Copies a file or a whole directory from one storage to another.
+fn copy_across(from_storage: &dyn Storage, from: &Path, to_storage: &dyn Storage, to: &Path) -> Result<()> {
+    if from_storage.stat(from)?.is_dir {
+        to_storage.mkdir(to)?;
+        for entry in from_storage.list(from)? {
+            copy_across(from_storage, &from.join(&entry.name), to_storage, &to.join(&entry.name))?;
+        }
+        return Ok(());
+    }
+    let mut reader = from_storage.read(from)?;
+    let mut writer = to_storage.write(to)?;
+    std::io::copy(&mut reader, &mut writer)?;
+    writer.flush()
+}
+
+impl Storage for ShareStorage {
+    fn list(&self, path: &Path) -> Result<Vec<Entry>> {
+        match self.target(path)? {
+            Target::Root => Ok(self
+                .shares
+                .iter()
+                .map(|share| Entry {
+                    name: share.name.clone(),
+                    metadata: share.storage.stat(Path::new("/")).unwrap_or_else(|_| self.root_metadata()),
+                })
+                .collect()),
+            Target::Inner(storage, rest, _) => storage.list(&rest),
+        }
+    }
+
+    fn stat(&self, path: &Path) -> Result<Metadata> {
+        match self.target(path)? {
+            Target::Root => Ok(self.root_metadata()),
+            Target::Inner(storage, rest, _) => storage.stat(&rest),
+        }
+    }
+
+    fn read(&self, path: &Path) -> Result<Box<dyn Read + Send>> {
+        match self.target(path)? {
+            Target::Root => Err(Error::new(ErrorKind::IsADirectory, "Is a directory")),
+            Target::Inner(storage, rest, _) => storage.read(&rest),
+        }
+    }
+
+    fn write(&self, path: &Path) -> Result<Box<dyn Write + Send>> {
+        let (storage, rest) = self.writable(path)?;
+        storage.write(&rest)
+    }
+
+    fn write_at(&self, path: &Path, offset: u64) -> Result<Box<dyn Write + Send>> {
+        let (storage, rest) = self.writable(path)?;
+        storage.write_at(&rest, offset)
+    }
+
+    /// Within one backend this is a plain rename. Between shares, or into the trash and
+    /// versions, the item is copied over and then deleted.
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let (from_storage, from_rest) = self.writable(from)?;
+        let (to_storage, to_rest) = self.writable(to)?;
+        if std::ptr::addr_eq(from_storage, to_storage) {
+            return from_storage.rename(&from_rest, &to_rest);
+        }
+        copy_across(from_storage, &from_rest, to_storage, &to_rest)?;
+        from_storage.delete(&from_rest)
+    }
+
+    fn delete(&self, path: &Path) -> Result<()> {
+        let (storage, rest) = self.writable(path)?;
+        storage.delete(&rest)
+    }
+
+    fn mkdir(&self, path: &Path) -> Result<()> {
+        let (storage, rest) = self.writable(path)?;
+        storage.mkdir(&rest)
+    }
+
+    /// Nothing can be stored in the virtual root or in read-only shares.
+    fn available(&self, path: &Path) -> Result<u64> {
+        match self.target(path)? {
+            Target::Root => Ok(0),
+            Target::Inner(_, _, Some(share)) if share.read_only => Ok(0),
+            Target::Inner(storage, rest, _) => storage.available(&rest),
+        }
+    }
+
+    fn local_file(&self, path: &Path) -> Option<std::fs::File> {
+        match self.target(path).ok()? {
+            Target::Root => None,
+            Target::Inner(storage, rest, _) => storage.local_file(&rest),
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Result, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
+use crate::utils::strip_telnet;
+
+/// Size of the buffers moving file data between the disk and data connections.
+pub const BUFFER_SIZE: usize = 256 * 1024;
+
+/// Transfer mode negotiated with MODE.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransferMode {
+    /// MODE S: raw bytes.
+    Stream,
+    /// MODE Z: a zlib (deflate) stream at the given compression level.
+    Deflate(u32),
+}
+
+/// Writing end of a data connection, compressing in MODE Z.
+pub enum DataWriter<'a> {
+    Stream(&'a mut TcpStream),
+    Deflate(ZlibEncoder<&'a mut TcpStream>),
+}
+
+impl<'a> DataWriter<'a> {
+    pub fn new(stream: &'a mut TcpStream, mode: TransferMode) -> DataWriter<'a> {
+        match mode {
+            TransferMode::Stream => DataWriter::Stream(stream),
+            TransferMode::Deflate(level) => DataWriter::Deflate(ZlibEncoder::new(stream, Compression::new(level))),
+        }
+    }
+
+    /// Flushes everything, including the end of the deflate stream.
+    pub fn finish(self) -> Result<()> {
+        match self {
+            DataWriter::Stream(stream) => stream.flush(),
+            DataWriter::Deflate(encoder) => encoder.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for DataWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            DataWriter::Stream(stream) => stream.write(buf),
+            DataWriter::Deflate(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            DataWriter::Stream(stream) => stream.flush(),
+            DataWriter::Deflate(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Reading end of a data connection, inflating in MODE Z.
+pub enum DataReader<'a> {
+    Stream(&'a mut TcpStream),
+    Deflate(ZlibDecoder<&'a mut TcpStream>),
+}
+
+impl<'a> DataReader<'a> {
+    pub fn new(stream: &'a mut TcpStream, mode: TransferMode) -> DataReader<'a> {
+        match mode {
+            TransferMode::Stream => DataReader::Stream(stream),
+            TransferMode::Deflate(_) => DataReader::Deflate(ZlibDecoder::new(stream)),
+        }
+    }
+}
+
+impl Read for DataReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            DataReader::Stream(stream) => stream.read(buf),
+            DataReader::Deflate(decoder) => decoder.read(buf),
+        }
+    }
+}
+
+/// Sends a whole file over a data connection in stream mode. On Linux, the kernel
+/// copies it with `sendfile`, without going through user space.
+#[cfg(target_os = "linux")]
+pub fn send_file(file: &File, socket: &TcpStream, progress: &mut dyn FnMut(usize)) -> Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // Bytes per call, so progress is reported while sending large files.
+    const CHUNK: usize = 4 * 1024 * 1024;
+    loop {
+        // SAFETY: both descriptors stay open during the call, and with a null offset
+        // sendfile reads from, and advances, the file's own position.
+        let sent = unsafe { libc::sendfile(socket.as_raw_fd(), file.as_raw_fd(), std::ptr::null_mut(), CHUNK) };
+        match sent {
+            0 => return Ok(()),
+            sent if sent > 0 => progress(sent as usize),
+            _ => {
+                let e = std::io::Error::last_os_error();
+                if e.kind() != std::io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+/// Sends a whole file over a data connection in stream mode.
+#[cfg(not(target_os = "linux"))]
+pub fn send_file(mut file: &File, mut socket: &TcpStream, progress: &mut dyn FnMut(usize)) -> Result<()> {
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            return Ok(());
+        }
+        socket.write_all(&buffer[..n])?;
+        progress(n);
+    }
+}
+
+/// Holds a transfer to an average rate, by sleeping whenever it gets ahead.
+pub struct Throttle {
+    bytes_per_sec: u64,
+    start: Instant,
+    sent: u64,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: u64) -> Throttle {
+        Throttle {
+            bytes_per_sec: bytes_per_sec.max(1),
+            start: Instant::now(),
+            sent: 0,
+        }
+    }
+
+    /// Bytes to move at once: about a tenth of a second's worth, so the rate stays smooth.
+    pub fn chunk(&self) -> usize {
+        (self.bytes_per_sec / 10).clamp(4096, BUFFER_SIZE as u64) as usize
+    }
+
+    /// Accounts for `n` bytes sent, and waits until they're due.
+    pub fn pace(&mut self, n: usize) {
+        self.sent += n as u64;
+        let due = Duration::from_secs_f64(self.sent as f64 / self.bytes_per_sec as f64);
+        if let Some(ahead) = due.checked_sub(self.start.elapsed()) {
+            thread::sleep(ahead);
+        }
+    }
+}
+
+/// Watches the control connection during a transfer, and shuts the data connection
+/// down when ABOR arrives, which ends the transfer loop wherever it's blocked.
+///
+/// The watcher only peeks, so ABOR is still read and answered as a command afterwards.
+/// It exits on the next data received after the transfer, or when the connection closes.
+pub struct AbortWatch {
+    /// The data connection, until the transfer ends: the watcher mustn't keep it open.
+    data: Arc<Mutex<Option<TcpStream>>>,
+    aborted: Arc<AtomicBool>,
+}
+
+impl AbortWatch {
+    pub fn start(control: &TcpStream, data: &TcpStream) -> Result<AbortWatch> {
+        let control = control.try_clone()?;
+        let watch = AbortWatch {
+            data: Arc::new(Mutex::new(Some(data.try_clone()?))),
+            aborted: Arc::new(AtomicBool::new(false)),
+        };
+
+        let data = Arc::clone(&watch.data);
+        let aborted = Arc::clone(&watch.aborted);
+        thread::spawn(move || {
+            let mut buffer = [0u8; 512];
+            while let Ok(n) = control.peek(&mut buffer) {
+                let guard = data.lock().unwrap_or_else(PoisonError::into_inner);
+                let Some(stream) = guard.as_ref().filter(|_| n > 0) else {
+                    break;
+                };
+                // Peeking stops at the urgent mark of a Synch, so the Telnet IP sent before
+                // it counts as an abort, as well as the ABOR that follows.
+                let interrupt = buffer[..n].windows(2).any(|pair| pair == [0xFF, 0xF4]);
+                let pending = strip_telnet(&buffer[..n]);
+                if interrupt || pending.split(|&byte| byte == b'\n').any(|line| line.trim_ascii().eq_ignore_ascii_case(b"ABOR")) {
+                    aborted.store(true, Ordering::SeqCst);
+                    let _ = stream.shutdown(Shutdown::Both);
+                    break;
+                }
+                drop(guard);
+                // Something else is waiting: it will be read after the transfer.
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+        Ok(watch)
+    }
+
+    pub fn aborted(&self) -> bool {
+        self.aborted.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for AbortWatch {
+    fn drop(&mut self) {
+        self.data.lock().unwrap_or_else(PoisonError::into_inner).take();
+    }
+}
+
+/// Segmented uploads in progress: the file was created by a STOR shorter than its ALLO,
+/// and REST segments from other connections fill it in. The upload is only complete,
+/// and announced, once the segments add up to the announced size.
+#[derive(Default)]
+pub struct Segments {
+    pending: Mutex<HashMap<PathBuf, Upload>>,
+}
+
+struct Upload {
+    size: u64,
+    received: u64,
+}
+
+impl Segments {
+    /// Starts waiting for the segments of `path`, `received` bytes of `size` being in.
+    pub fn expect(&self, path: &Path, size: u64, received: u64) {
+        self.pending.lock().unwrap_or_else(PoisonError::into_inner).insert(path.to_path_buf(), Upload { size, received });
+    }
+
+    /// Forgets an upload, as `path` was stored again in one piece.
+    pub fn cancel(&self, path: &Path) {
+        self.pending.lock().unwrap_or_else(PoisonError::into_inner).remove(path);
+    }
+
+    /// Counts a segment stored into `path`. True when it completes a segmented upload;
+    /// false as well for a segment of no known upload, such as a resumed one.
+    pub fn add(&self, path: &Path, bytes: u64) -> bool {
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(upload) = pending.get_mut(path) else {
+            return false;
+        };
+        upload.received += bytes;
+        if upload.received < upload.size {
+            return false;
+        }
+        pending.remove(path);
+        true
+    }
+}
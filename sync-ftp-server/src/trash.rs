@@ -0,0 +1,160 @@
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::{TrashConfig, META_DIR};
+use crate::storage::{self, Storage};
+
+/// Per-user trash living in `/.ventus/trash/<user>` in the storage.
+///
+/// Deleted items are moved to `files/<id>` and their original location is
+/// recorded in `info/<id>`, so they can be listed, restored or purged later.
+pub struct Trash {
+    storage: Arc<dyn Storage>,
+    files: PathBuf,
+    info: PathBuf,
+    retention: Option<Duration>,
+}
+
+#[derive(Debug)]
+pub struct TrashEntry {
+    pub id: String,
+    pub path: PathBuf,
+    pub deleted: u64,
+}
+
+impl Trash {
+    pub fn new(storage: Arc<dyn Storage>, user: &str, config: &TrashConfig) -> Trash {
+        let user: String = user
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect();
+        let root = Path::new("/").join(META_DIR).join("trash").join(user);
+
+        Trash {
+            storage,
+            files: root.join("files"),
+            info: root.join("info"),
+            retention: match config.retention_days {
+                0 => None,
+                days => Some(Duration::from_secs(days * 24 * 60 * 60)),
+            },
+        }
+    }
+
+    /// Moves `path` (a file or directory) into the trash.
+    pub fn put(&self, path: &Path) -> Result<String, Error> {
+        self.purge_expired();
+        storage::create_dir_all(&*self.storage, &self.files)?;
+        storage::create_dir_all(&*self.storage, &self.info)?;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let id = format!("{}", now.as_nanos());
+        self.storage.rename(path, &self.files.join(&id))?;
+        storage::write_all(
+            &*self.storage,
+            &self.info.join(&id),
+            format!("path={}\ndeleted={}\n", path.display(), now.as_secs()).as_bytes(),
+        )?;
+        Ok(id)
+    }
+
+    pub fn list(&self) -> Result<Vec<TrashEntry>, Error> {
+        self.purge_expired();
+        let mut entries = Vec::new();
+        let dir = match self.storage.list(&self.info) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(entries),
+            Err(e) => return Err(e),
+        };
+        for entry in dir {
+            if let Ok(entry) = self.get(&entry.name) {
+                entries.push(entry);
+            }
+        }
+        entries.sort_by_key(|entry| entry.deleted);
+        Ok(entries)
+    }
+
+    /// Moves a trashed item back to its original path, which is returned.
+    pub fn restore(&self, id: &str) -> Result<PathBuf, Error> {
+        let entry = self.get(id)?;
+        if self.storage.stat(&entry.path).is_ok() {
+            return Err(Error::new(ErrorKind::AlreadyExists, "Target already exists"));
+        }
+        if let Some(parent) = entry.path.parent() {
+            storage::create_dir_all(&*self.storage, parent)?;
+        }
+        self.storage.rename(&self.files.join(id), &entry.path)?;
+        self.storage.delete(&self.info.join(id))?;
+        Ok(entry.path)
+    }
+
+    /// Permanently deletes one item, or the whole trash when `id` is `None`.
+    /// Returns the number of purged items.
+    pub fn purge(&self, id: Option<&str>) -> Result<usize, Error> {
+        match id {
+            Some(id) => {
+                self.get(id)?;
+                self.remove(id)?;
+                Ok(1)
+            }
+            None => {
+                let entries = self.list()?;
+                for entry in &entries {
+                    self.remove(&entry.id)?;
+                }
+                Ok(entries.len())
+            }
+        }
+    }
+
+    fn purge_expired(&self) {
+        let Some(retention) = self.retention else {
+            return;
+        };
+        let Ok(dir) = self.storage.list(&self.info) else {
+            return;
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        for entry in dir {
+            let id = entry.name;
+            if let Ok(entry) = self.get(&id) {
+                if now.saturating_sub(entry.deleted) > retention.as_secs() {
+                    println!("[*] Purging expired trash item {}", entry.path.display());
+                    let _ = self.remove(&id);
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Result<TrashEntry, Error> {
+        if id.is_empty() || id.contains(['/', '\\', '.']) {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid trash id"));
+        }
+        let info = storage::read_to_string(&*self.storage, &self.info.join(id))?;
+        let mut path = None;
+        let mut deleted = 0;
+        for line in info.lines() {
+            if let Some(value) = line.strip_prefix("path=") {
+                path = Some(PathBuf::from(value));
+            } else if let Some(value) = line.strip_prefix("deleted=") {
+                deleted = value.parse().unwrap_or(0);
+            }
+        }
+
+        match path {
+            Some(path) => Ok(TrashEntry { id: id.to_string(), path, deleted }),
+            None => Err(Error::new(ErrorKind::InvalidData, "Corrupted trash entry")),
+        }
+    }
+
+    fn remove(&self, id: &str) -> Result<(), Error> {
+        match self.storage.delete(&self.files.join(id)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        self.storage.delete(&self.info.join(id))
+    }
+}
@@ -1,40 +1,194 @@
-use std::net::TcpStream;
-use std::io::{Read, Write};
+use std::fmt;
+use std::io::{ErrorKind, Read, Write};
 use crate::command::ResultCode;
 
-pub fn send_cmd(stream: &mut TcpStream, code: ResultCode, message: &str) {
-    let msg = if message.is_empty() {
-        format!("{} \r\n", code as u32)
-    } else {
-        format!("{} {}\r\n", code as u32, message)
-    };
+/// A control-channel reply. Replies with more than one line are sent in the
+/// RFC 959 multi-line format: `NNN-first`, ` middle`..., `NNN last`.
+pub struct Reply {
+    code: ResultCode,
+    lines: Vec<String>,
+}
+
+impl Reply {
+    pub fn new(code: ResultCode, message: &str) -> Reply {
+        Reply {
+            code,
+            lines: vec![message.to_string()],
+        }
+    }
 
+    /// Appends a line; the last line pushed closes the reply.
+    pub fn push(&mut self, line: impl Into<String>) {
+        self.lines.push(line.into());
+    }
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = self.code as u32;
+        match self.lines.split_last() {
+            Some((last, [])) if last.is_empty() => write!(f, "{} \r\n", code),
+            Some((last, rest)) => {
+                for (i, line) in rest.iter().enumerate() {
+                    if i == 0 {
+                        write!(f, "{}-{}\r\n", code, line)?;
+                    } else {
+                        write!(f, " {}\r\n", line)?;
+                    }
+                }
+                write!(f, "{} {}\r\n", code, last)
+            }
+            None => write!(f, "{} \r\n", code),
+        }
+    }
+}
+
+pub fn send_reply(stream: &mut impl Write, reply: &Reply) {
+    let msg = reply.to_string();
     println!("<--- {}", msg);
-    write!(stream, "{}", msg).unwrap();
+    // A broken control connection ends the session when the next command is read.
+    if let Err(e) = write!(stream, "{}", msg) {
+        println!("[!] Couldn't send reply: {}", e);
+    }
+}
+
+pub fn send_cmd(stream: &mut impl Write, code: ResultCode, message: &str) {
+    send_reply(stream, &Reply::new(code, message));
 }
 
-pub fn read_all_message(stream: &mut TcpStream) -> Vec<u8> {
-    let mut out = Vec::with_capacity(100);
-    let mut buf = [0u8; 1];
+/// Longest command line accepted, without its line break.
+pub const MAX_LINE_LENGTH: usize = 500;
 
-    loop {
-        match stream.read(&mut buf) {
-            Ok(received) if received > 0 => {
-                if out.is_empty() && buf[0] == b' ' {
-                    continue
+/// A line read from the control connection by `LineReader`.
+pub enum Line {
+    /// A command, without its line break and leading spaces.
+    Command(Vec<u8>),
+    /// A line longer than `MAX_LINE_LENGTH`, which was dropped.
+    TooLong,
+    /// The connection was closed.
+    Closed,
+}
+
+/// Splits the control connection into lines, ended by CRLF or a bare LF. It reads in
+/// blocks and keeps whatever follows a line for the next one, so commands sent together
+/// in one packet are all handled, in order.
+#[derive(Default)]
+pub struct LineReader {
+    buffer: Vec<u8>,
+    /// Set while dropping the rest of an overlong line, up to its line break.
+    overlong: bool,
+}
+
+impl LineReader {
+    pub fn read_line(&mut self, stream: &mut impl Read) -> Line {
+        loop {
+            if let Some(end) = self.buffer.iter().position(|&byte| byte == b'\n') {
+                let mut line: Vec<u8> = self.buffer.drain(..=end).collect();
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                if std::mem::take(&mut self.overlong) || line.len() > MAX_LINE_LENGTH {
+                    return Line::TooLong;
                 }
+                let start = line.iter().position(|&byte| byte != b' ').unwrap_or(line.len());
+                line.drain(..start);
+                return Line::Command(line);
+            }
+            // The buffer holds the start of a single line: past the limit, stop keeping it.
+            if self.buffer.len() > MAX_LINE_LENGTH {
+                self.buffer.clear();
+                self.overlong = true;
+            }
 
-                out.push(buf[0]);
+            let mut chunk = [0u8; 1024];
+            match stream.read(&mut chunk) {
+                Ok(0) => return Line::Closed,
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => return Line::Closed,
             }
+        }
+    }
+}
 
-            _ => return Vec::new(),
+/// Removes Telnet commands from a control line: clients send IAC IP and IAC DM (the
+/// Synch) before ABOR. An escaped IAC IAC stands for one 0xFF byte.
+pub fn strip_telnet(line: &[u8]) -> Vec<u8> {
+    const IAC: u8 = 0xFF;
+    let mut out = Vec::with_capacity(line.len());
+    let mut bytes = line.iter().copied().peekable();
+    while let Some(byte) = bytes.next() {
+        if byte != IAC {
+            out.push(byte);
+            continue;
         }
+        match bytes.peek() {
+            Some(&IAC) => {
+                out.push(IAC);
+                bytes.next();
+            }
+            // A command byte. The DM of a Synch may be missing, as it's sent urgent.
+            Some(&command) if command >= 0xF0 => {
+                bytes.next();
+            }
+            _ => {}
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
 
-        let len = out.len();
-        if len > 1 && out[len - 2] == b'\r' && out[len - 1] == b'\n' {
-            out.pop();
-            out.pop();
-            return out;
+    use super::*;
+
+    fn command(line: Line) -> Vec<u8> {
+        match line {
+            Line::Command(command) => command,
+            Line::TooLong => panic!("line too long"),
+            Line::Closed => panic!("connection closed"),
         }
     }
+
+    #[test]
+    fn reply_formats() {
+        assert_eq!(Reply::new(ResultCode::Ok, "Done").to_string(), "200 Done\r\n");
+        assert_eq!(Reply::new(ResultCode::Ok, "").to_string(), "200 \r\n");
+
+        let mut reply = Reply::new(ResultCode::Ok, "First");
+        reply.push("Middle");
+        reply.push("Last");
+        assert_eq!(reply.to_string(), "200-First\r\n Middle\r\n200 Last\r\n");
+    }
+
+    #[test]
+    fn line_reader_splits_lines() {
+        let mut stream = Cursor::new(b"USER bob\r\nPASS x\n   NOOP\r\n".to_vec());
+        let mut lines = LineReader::default();
+        assert_eq!(command(lines.read_line(&mut stream)), b"USER bob");
+        assert_eq!(command(lines.read_line(&mut stream)), b"PASS x");
+        assert_eq!(command(lines.read_line(&mut stream)), b"NOOP");
+        assert!(matches!(lines.read_line(&mut stream), Line::Closed));
+    }
+
+    #[test]
+    fn line_reader_drops_overlong_lines() {
+        let mut input = vec![b'a'; MAX_LINE_LENGTH + 1];
+        input.extend_from_slice(b"\r\n");
+        input.extend(vec![b'b'; 4 * MAX_LINE_LENGTH]);
+        input.extend_from_slice(b"\r\nNOOP\r\n");
+        let mut stream = Cursor::new(input);
+        let mut lines = LineReader::default();
+        assert!(matches!(lines.read_line(&mut stream), Line::TooLong));
+        assert!(matches!(lines.read_line(&mut stream), Line::TooLong));
+        assert_eq!(command(lines.read_line(&mut stream)), b"NOOP");
+    }
+
+    #[test]
+    fn telnet_commands_are_stripped() {
+        assert_eq!(strip_telnet(b"\xff\xf4\xff\xf2ABOR"), b"ABOR");
+        assert_eq!(strip_telnet(b"a\xff\xffb"), b"a\xffb");
+    }
 }
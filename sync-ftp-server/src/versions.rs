@@ -0,0 +1,115 @@
+use std::cmp::Reverse;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::{VersionsConfig, META_DIR};
+use crate::storage::{self, Storage};
+
+/// Hidden store of previous file contents, in `/.ventus/versions` in the storage.
+///
+/// The versions of `/docs/a.txt` live in `versions/docs/a.txt.v/<id>`, where the id is
+/// the time the version was replaced, in nanoseconds.
+pub struct Versions {
+    storage: Arc<dyn Storage>,
+    root: PathBuf,
+    keep: usize,
+    max_age: Option<Duration>,
+}
+
+#[derive(Debug)]
+pub struct Version {
+    pub id: String,
+    pub size: u64,
+    pub created: u64,
+}
+
+impl Versions {
+    pub fn new(storage: Arc<dyn Storage>, config: &VersionsConfig) -> Versions {
+        Versions {
+            storage,
+            root: Path::new("/").join(META_DIR).join("versions"),
+            keep: config.keep,
+            max_age: match config.max_age_days {
+                0 => None,
+                days => Some(Duration::from_secs(days * 24 * 60 * 60)),
+            },
+        }
+    }
+
+    /// Moves the current content of `path` into the store before it gets overwritten.
+    pub fn save(&self, path: &Path) -> Result<String, Error> {
+        let dir = self.dir(path)?;
+        storage::create_dir_all(&*self.storage, &dir)?;
+
+        let id = format!("{}", SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos());
+        self.storage.rename(path, &dir.join(&id))?;
+        self.prune(path)?;
+        Ok(id)
+    }
+
+    /// Versions of `path`, newest first.
+    pub fn list(&self, path: &Path) -> Result<Vec<Version>, Error> {
+        let dir = self.dir(path)?;
+        let mut versions = Vec::new();
+        let entries = match self.storage.list(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(versions),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let Ok(nanos) = entry.name.parse::<u128>() else {
+                continue;
+            };
+            versions.push(Version {
+                id: entry.name,
+                size: entry.metadata.len,
+                created: (nanos / 1_000_000_000) as u64,
+            });
+        }
+        versions.sort_by_key(|version| Reverse(version.id.parse::<u128>().unwrap_or(0)));
+        Ok(versions)
+    }
+
+    /// Replaces `path` with its version `id`. The replaced content is kept as a new
+    /// version, so a restore can itself be undone.
+    pub fn restore(&self, path: &Path, id: &str) -> Result<(), Error> {
+        if id.is_empty() || !id.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid version id"));
+        }
+        let version = self.dir(path)?.join(id);
+        if !self.storage.stat(&version).is_ok_and(|metadata| !metadata.is_dir) {
+            return Err(Error::new(ErrorKind::NotFound, "No such version"));
+        }
+
+        let staged = storage::staged_path(path, &format!("restore-{}", id));
+        storage::copy(&*self.storage, &version, &staged)?;
+        if self.storage.stat(path).is_ok_and(|metadata| !metadata.is_dir) {
+            self.save(path)?;
+        }
+        self.storage.rename(&staged, path)
+    }
+
+    /// Drops versions that are neither among the `keep` newest nor younger than `max_age`.
+    fn prune(&self, path: &Path) -> Result<(), Error> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let dir = self.dir(path)?;
+        for (i, version) in self.list(path)?.iter().enumerate() {
+            let young = self
+                .max_age
+                .is_some_and(|max_age| now.saturating_sub(version.created) <= max_age.as_secs());
+            if i >= self.keep && !young {
+                self.storage.delete(&dir.join(&version.id))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn dir(&self, path: &Path) -> Result<PathBuf, Error> {
+        let relative = path.strip_prefix("/").unwrap_or(path);
+        let name = relative.file_name().ok_or(ErrorKind::InvalidInput)?;
+        let parent = relative.parent().unwrap_or(Path::new(""));
+        Ok(self.root.join(parent).join(format!("{}.v", name.to_string_lossy())))
+    }
+}